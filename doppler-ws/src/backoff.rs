@@ -0,0 +1,81 @@
+//! Delays between retries.
+
+use std::time::Duration;
+
+/// How long to wait before each retry of an operation that failed.
+///
+/// The first retry waits `base`, and each one after waits `multiplier` times
+/// longer than the last, up to `max`. With `jitter`, each delay is shortened
+/// by a random fraction of up to that much, so clients that failed at the
+/// same time don't all retry at the same time.
+///
+/// The default waits 500ms, then 1s, 2s and so on up to 30s, without jitter.
+///
+/// ```
+/// # use std::time::Duration;
+/// # use doppler_ws::backoff::Backoff;
+/// let backoff = Backoff {
+///     base: Duration::from_secs(1),
+///     max: Duration::from_secs(5),
+///     ..Backoff::default()
+/// };
+/// assert_eq!(backoff.delay(1), Duration::from_secs(1));
+/// assert_eq!(backoff.delay(2), Duration::from_secs(2));
+/// assert_eq!(backoff.delay(3), Duration::from_secs(4));
+/// assert_eq!(backoff.delay(4), Duration::from_secs(5));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Backoff {
+    /// The delay before the first retry.
+    pub base: Duration,
+    /// The longest delay before any retry.
+    pub max: Duration,
+    /// How much longer each delay is than the one before.
+    pub multiplier: f64,
+    /// The largest fraction, from 0 to 1, that a delay is randomly shortened
+    /// by.
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.0,
+        }
+    }
+}
+
+impl Backoff {
+    /// Gets the delay before the given retry, counting from 1, without
+    /// jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let exponent = i32::try_from(retry.saturating_sub(1)).unwrap_or(i32::MAX);
+        let factor = self.multiplier.max(1.0).powi(exponent);
+        // Saturates rather than panicking once the delay gets too long
+        Duration::try_from_secs_f64(self.base.as_secs_f64() * factor)
+            .unwrap_or(Duration::MAX)
+            .min(self.max)
+    }
+
+    /// Gets the delay before the given retry, counting from 1, with jitter
+    /// applied.
+    pub fn jittered_delay(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - jitter * fastrand::f64())
+    }
+
+    /// Waits before the given retry, counting from 1.
+    ///
+    /// This sleeps with Tokio's timer, so tests can skip through the delays
+    /// with a paused clock.
+    pub async fn wait(&self, retry: u32) {
+        tokio::time::sleep(self.jittered_delay(retry)).await;
+    }
+}
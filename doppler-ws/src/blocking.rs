@@ -0,0 +1,437 @@
+//! A blocking API for applications that don't use async.
+//!
+//! The clients here wrap their async counterparts, driving them on a
+//! current-thread tokio runtime owned by the client. A [`DeviceClient`] shares
+//! the runtime of the [`TransferClient`] it was paired with, as its
+//! connections are tied to it.
+//!
+//! These clients must not be used from within an async runtime, as blocking on
+//! a future there will panic.
+
+use std::{path::Path, sync::Arc};
+
+use mime::Mime;
+use tokio::runtime::Runtime;
+
+use crate::{
+    connection::{ConnectOptions, TransferClientBuilder},
+    device, model,
+};
+
+/// A blocking connection to the Wi-Fi Transfer API. This is used solely for
+/// pairing.
+///
+/// See [`crate::TransferClient`] for details on each method.
+pub struct TransferClient {
+    inner: crate::TransferClient,
+    runtime: Arc<Runtime>,
+}
+
+impl TransferClient {
+    /// Connects to the Doppler Transfer API.
+    pub fn connect() -> crate::Result<Self> {
+        TransferClientBuilder::default().connect_blocking()
+    }
+
+    /// Connects to the Doppler Transfer API, identifying as the given client
+    /// ID rather than a random one.
+    pub fn connect_with_id(id: crate::Uuid) -> crate::Result<Self> {
+        TransferClientBuilder::default()
+            .client_id(id)
+            .connect_blocking()
+    }
+
+    /// Connects to the Doppler Transfer API, using the given options such as
+    /// a proxy or custom TLS configuration.
+    pub fn connect_with(options: ConnectOptions) -> crate::Result<Self> {
+        TransferClientBuilder::from(options).connect_blocking()
+    }
+
+    /// Connects to the Doppler Transfer API with the builder's settings.
+    pub(crate) fn from_builder(builder: TransferClientBuilder) -> crate::Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let inner = runtime.block_on(builder.connect())?;
+        Ok(Self {
+            inner,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Returns a reference to the device pairing code.
+    pub fn code(&self) -> &str {
+        self.inner.code()
+    }
+
+    /// Renders the pairing code as a QR code in SVG.
+    #[cfg(feature = "qr")]
+    pub fn qr_svg(&self) -> String {
+        self.inner.qr_svg()
+    }
+
+    /// Returns the number of messages received but not yet consumed.
+    pub fn pending_messages(&self) -> usize {
+        self.inner.pending_messages()
+    }
+
+    /// Requests a new pairing code and returns it.
+    pub fn refresh_code(&mut self) -> crate::Result<&str> {
+        self.runtime.block_on(self.inner.refresh_code())
+    }
+
+    /// Completes the pairing process. If successful, the connection to the
+    /// device is returned, along with whether the device asked to be saved.
+    ///
+    /// If the device was already saved, set `is_saved` to true.
+    pub fn confirm_device(
+        &mut self,
+        device: &mut model::DeviceResponse,
+        is_saved: bool,
+    ) -> crate::Result<PairedDevice> {
+        let paired = self
+            .runtime
+            .block_on(self.inner.confirm_device(device, is_saved))?;
+        Ok(self.wrap_paired(paired))
+    }
+
+    /// Completes the pairing process without modifying the response. If
+    /// successful, the connection to the device is returned, along with
+    /// whether the device asked to be saved.
+    pub fn confirm(
+        &mut self,
+        device: &model::DeviceResponse,
+        is_saved: bool,
+    ) -> crate::Result<PairedDevice> {
+        let paired = self
+            .runtime
+            .block_on(self.inner.confirm(device, is_saved))?;
+        Ok(self.wrap_paired(paired))
+    }
+
+    fn wrap_paired(&self, paired: device::PairedDevice) -> PairedDevice {
+        PairedDevice {
+            client: DeviceClient {
+                inner: paired.client,
+                runtime: self.runtime.clone(),
+            },
+            save_requested: paired.save_requested,
+        }
+    }
+
+    /// Waits for a device to pair with the pairing code.
+    pub fn get_new_device(&mut self) -> crate::Result<model::DeviceResponse> {
+        self.runtime.block_on(self.inner.get_new_device())
+    }
+
+    /// Initiates the pairing process with a saved device by sending it a push
+    /// notification.
+    pub fn get_saved_device(
+        &mut self,
+        device: &model::Device,
+    ) -> crate::Result<model::DeviceResponse> {
+        self.runtime.block_on(self.inner.get_saved_device(device))
+    }
+
+    /// Sends a saved device the push notification asking it to pair again.
+    pub fn resend_push(&self, device: &model::Device) -> crate::Result<()> {
+        self.runtime.block_on(self.inner.resend_push(device))
+    }
+
+    /// Waits for a saved device to respond to a push notification, giving up
+    /// after `timeout`.
+    pub fn wait_for_saved_device(
+        &mut self,
+        device: &model::Device,
+        timeout: Option<std::time::Duration>,
+    ) -> crate::Result<model::DeviceResponse> {
+        self.runtime
+            .block_on(self.inner.wait_for_saved_device(device, timeout))
+    }
+
+    /// Closes the connection to the API with a close handshake.
+    pub fn close(self) -> crate::Result<()> {
+        self.runtime.block_on(self.inner.close())
+    }
+}
+
+/// The result of a completed pairing.
+///
+/// See [`device::PairedDevice`] for details.
+pub struct PairedDevice {
+    pub client: DeviceClient,
+    pub save_requested: bool,
+}
+
+/// A blocking connection to a Doppler device.
+///
+/// See [`device::DeviceClient`] for details on each method.
+pub struct DeviceClient {
+    inner: device::DeviceClient,
+    runtime: Arc<Runtime>,
+}
+
+impl DeviceClient {
+    /// Shuts the client down, closing its pooled connections to the device.
+    pub fn shutdown(self) {
+        self.inner.shutdown();
+    }
+
+    /// Sets whether uploads should fail when the device reports a checksum
+    /// that differs from the data sent.
+    pub fn set_verify_checksum(&mut self, verify: bool) {
+        self.inner.set_verify_checksum(verify);
+    }
+
+    /// Sets how file names are normalized before uploading.
+    pub fn set_name_normalization(&mut self, normalization: device::NameNormalization) {
+        self.inner.set_name_normalization(normalization);
+    }
+
+    /// Sets the names of the multipart fields used to upload the file name
+    /// and data.
+    pub fn set_upload_field_names(
+        &mut self,
+        filename_field: impl Into<String>,
+        file_field: impl Into<String>,
+    ) {
+        self.inner
+            .set_upload_field_names(filename_field, file_field);
+    }
+
+    /// Sets how often progress callbacks are called during uploads.
+    pub fn set_progress_throttle(&mut self, throttle: device::ProgressThrottle) {
+        self.inner.set_progress_throttle(throttle);
+    }
+
+    /// Sets the playlist every upload is added to, or `None` to not name one.
+    #[cfg(feature = "playlists")]
+    pub fn set_playlist(&mut self, playlist: Option<String>) {
+        self.inner.set_playlist(playlist);
+    }
+
+    /// Checks that the device is still reachable by requesting its info.
+    pub fn ping(&self) -> crate::Result<()> {
+        self.runtime.block_on(self.inner.ping())
+    }
+
+    /// Returns a list of all MIME types reported as supported by the device.
+    pub fn supported_mimetypes(&self) -> &[String] {
+        self.inner.supported_mimetypes()
+    }
+
+    /// Returns the LAN URL the device is reached at.
+    pub fn lan_url(&self) -> &str {
+        self.inner.lan_url()
+    }
+
+    /// If the device requested to be saved, provides the device metadata
+    /// represented as the "push token" by the Doppler API.
+    pub fn push_token(&self) -> Option<&model::Device> {
+        self.inner.push_token()
+    }
+
+    /// Returns the host and port the device is reached at.
+    pub fn address(&self) -> &str {
+        self.inner.address()
+    }
+
+    /// Returns the info the device reported when connecting.
+    pub fn info(&self) -> &model::DeviceInfo {
+        self.inner.info()
+    }
+
+    /// Returns the info the device reported when connecting as the JSON it
+    /// sent.
+    pub fn info_raw(&self) -> &serde_json::Value {
+        self.inner.info_raw()
+    }
+
+    /// Returns the name of the device, as set by the user.
+    pub fn device_name(&self) -> &str {
+        self.inner.device_name()
+    }
+
+    /// Returns the name of the app running on the device.
+    pub fn app_name(&self) -> &str {
+        self.inner.app_name()
+    }
+
+    /// Returns the version of the Doppler app reported by the device.
+    pub fn app_version(&self) -> u32 {
+        self.inner.app_version()
+    }
+
+    /// Returns whether the device's app version is one this crate has been
+    /// tested with. None have been yet, so this is always `false` for now.
+    pub fn app_version_tested(&self) -> bool {
+        self.inner.app_version_tested()
+    }
+
+    /// Returns how many bytes of storage the device has free, if it reports
+    /// it. The device doesn't report this yet, so it's always `None`.
+    pub fn free_space(&self) -> Option<u64> {
+        self.inner.free_space()
+    }
+
+    /// Checks whether the given `Mime` is supported by the device.
+    pub fn mime_supported(&self, mime: &Mime) -> bool {
+        self.inner.mime_supported(mime)
+    }
+
+    /// Checks whether the given `Mime` is supported by the device, without
+    /// falling back to the `x-` prefixed type.
+    pub fn mime_supported_strict(&self, mime: &Mime) -> bool {
+        self.inner.mime_supported_strict(mime)
+    }
+
+    /// Returns a list of all file extensions reported as known by the device.
+    pub fn supported_extensions(&self) -> &[String] {
+        self.inner.supported_extensions()
+    }
+
+    /// Groups the file extensions known by the device for an open-file
+    /// dialog, labeled by kind.
+    pub fn dialog_filters(&self) -> Vec<(String, Vec<String>)> {
+        self.inner.dialog_filters()
+    }
+
+    /// Checks whether the given file path has a supported file extension.
+    pub fn extension_supported(&self, path: impl AsRef<Path>) -> bool {
+        self.inner.extension_supported(path)
+    }
+
+    /// Uploads a file to the device.
+    pub fn upload(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime
+            .block_on(self.inner.upload(filename, len, mime, data))
+    }
+
+    /// Uploads a file to the device, stopping early if `cancel` is
+    /// triggered, such as from another thread.
+    pub fn upload_cancellable(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+        cancel: &crate::CancellationToken,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime.block_on(
+            self.inner
+                .upload_cancellable(filename, len, mime, data, cancel),
+        )
+    }
+
+    /// Uploads the file at `path` to the device.
+    pub fn upload_file(
+        &self,
+        path: impl AsRef<Path>,
+        mime: Mime,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime.block_on(self.inner.upload_file(path, mime))
+    }
+
+    /// Uploads the file at `path` to the device, calling `on_progress` with
+    /// the size of each chunk as it's sent.
+    pub fn upload_file_with_progress(
+        &self,
+        path: impl AsRef<Path>,
+        mime: Mime,
+        on_progress: device::ProgressFn,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime.block_on(
+            self.inner
+                .upload_file_with_progress(path, mime, on_progress),
+        )
+    }
+
+    /// Uploads the file at `path` to the device under the file name `name`,
+    /// calling `on_progress` with the size of each chunk as it's sent.
+    pub fn upload_file_as(
+        &self,
+        path: impl AsRef<Path>,
+        name: impl AsRef<Path>,
+        mime: Mime,
+        on_progress: device::ProgressFn,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime
+            .block_on(self.inner.upload_file_as(path, name, mime, on_progress))
+    }
+
+    /// Uploads data read from `reader` to the device, using chunked transfer
+    /// encoding if `len` isn't known.
+    pub fn upload_from_reader(
+        &self,
+        filename: impl AsRef<Path>,
+        len: Option<u64>,
+        mime: Mime,
+        reader: impl tokio::io::AsyncRead + Send + Sync + 'static,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime
+            .block_on(self.inner.upload_from_reader(filename, len, mime, reader))
+    }
+
+    /// Uploads a file to the device with the given content type, rather than
+    /// a parsed `Mime`.
+    pub fn upload_with_content_type(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        content_type: &str,
+        data: impl Into<reqwest::Body>,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime.block_on(self.inner.upload_with_content_type(
+            filename,
+            len,
+            content_type,
+            data,
+        ))
+    }
+
+    /// Uploads a file to the device, along with optional cover art.
+    pub fn upload_with_artwork(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+        artwork: Option<(Mime, reqwest::Body)>,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime.block_on(
+            self.inner
+                .upload_with_artwork(filename, len, mime, data, artwork),
+        )
+    }
+
+    /// Uploads several files to the device, sending up to
+    /// [`device::MAX_BATCH_FILES`] of them in each request.
+    #[cfg(feature = "batch")]
+    pub fn upload_batch<P: AsRef<Path>, B: Into<reqwest::Body>>(
+        &self,
+        files: impl IntoIterator<Item = (P, u64, Mime, B)>,
+    ) -> crate::Result<Vec<device::UploadOutcome>> {
+        self.runtime.block_on(self.inner.upload_batch(files))
+    }
+
+    /// Uploads a file to the device, asking for it to be added to an existing
+    /// playlist.
+    #[cfg(feature = "playlists")]
+    pub fn upload_to(
+        &self,
+        playlist: &str,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+    ) -> crate::Result<device::UploadOutcome> {
+        self.runtime
+            .block_on(self.inner.upload_to(playlist, filename, len, mime, data))
+    }
+}
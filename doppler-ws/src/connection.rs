@@ -0,0 +1,489 @@
+//! Settings for how [`TransferClient`](crate::TransferClient) reaches the
+//! pairing API.
+
+#[cfg(any(test, feature = "mock"))]
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use std::{future::Future, time::Duration};
+
+use futures_util::{SinkExt, TryStreamExt};
+use http::HeaderValue;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_websockets::{ClientBuilder, Connector, MaybeTlsStream, Message, WebSocketStream};
+use uuid::Uuid;
+
+use crate::{backoff::Backoff, device::HttpVersion, error::ApiError, API_DOMAIN};
+
+/// Carries messages between a [`TransferClient`](crate::TransferClient) and
+/// the pairing API.
+///
+/// The API is normally reached over a websocket, but any transport can be
+/// used, such as a `ScriptedTransport` in tests. Messages are the JSON text
+/// the API sends and expects.
+pub trait Transport: Send {
+    /// Sends a message to the API.
+    fn send(&mut self, text: String) -> impl Future<Output = crate::Result<()>> + Send;
+
+    /// Waits for the next message from the API, or returns `None` if the
+    /// connection ended.
+    ///
+    /// This must be cancel safe: if the future is dropped before it
+    /// completes, no message may be lost. The client's waits for messages
+    /// rely on this to be cancel safe themselves.
+    fn recv(&mut self) -> impl Future<Output = crate::Result<Option<String>>> + Send;
+
+    /// Closes the connection, waiting for the other end to acknowledge it
+    /// if the transport has a close handshake.
+    fn close(&mut self) -> impl Future<Output = crate::Result<()>> + Send;
+}
+
+impl Transport for WsStream {
+    async fn send(&mut self, text: String) -> crate::Result<()> {
+        Ok(SinkExt::send(self, Message::text(text)).await?)
+    }
+
+    // Cancel safe, as a frame taken from the stream is returned or skipped
+    // without awaiting anything else
+    async fn recv(&mut self) -> crate::Result<Option<String>> {
+        while let Some(msg) = self.try_next().await? {
+            if let Some(text) = frame_text(&msg)? {
+                return Ok(Some(text));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn close(&mut self) -> crate::Result<()> {
+        SinkExt::close(self).await?;
+        // The API answers with its own close frame, after which the stream
+        // ends. Anything still arriving before then is of no use.
+        while let Some(msg) = self.try_next().await? {
+            tracing::trace!(
+                len = msg.as_payload().len(),
+                "Discarding message while closing"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Gets the text of a websocket frame. Only text frames carry API responses;
+/// a close frame becomes [`ApiError::ConnectionClosed`], and anything else is
+/// ignored.
+fn frame_text(msg: &Message) -> crate::Result<Option<String>> {
+    let kind = if msg.is_text() {
+        "text"
+    } else if msg.is_binary() {
+        "binary"
+    } else if msg.is_close() {
+        "close"
+    } else if msg.is_ping() {
+        "ping"
+    } else {
+        "pong"
+    };
+    tracing::trace!(kind, len = msg.as_payload().len(), "Received message");
+
+    if let Some(text) = msg.as_text() {
+        Ok(Some(text.to_string()))
+    } else if let Some((code, reason)) = msg.as_close() {
+        tracing::debug!(
+            code = u16::from(code),
+            reason,
+            "Pairing API closed the connection"
+        );
+        Err(ApiError::ConnectionClosed {
+            code: code.into(),
+            reason: reason.to_string(),
+        })
+    } else {
+        Ok(None)
+    }
+}
+
+/// A [`Transport`] that replays scripted messages from the API, for testing
+/// pairing flows without a connection.
+///
+/// Once the script runs out, the connection appears to have ended. The
+/// transport never waits, so the client's futures complete on their first
+/// poll.
+///
+/// ```
+/// use doppler_ws::{connection::ScriptedTransport, TransferClientBuilder};
+/// use futures_util::FutureExt;
+///
+/// // A response split across messages is put back together
+/// let transport = ScriptedTransport::new([r#"{"co"#, r#"de":"123456"}"#]);
+/// let client = TransferClientBuilder::default()
+///     .connect_over(transport)
+///     .now_or_never()
+///     .unwrap()
+///     .unwrap();
+/// assert_eq!(client.code(), "123456");
+/// ```
+#[cfg(any(test, feature = "mock"))]
+#[derive(Debug, Default)]
+pub struct ScriptedTransport {
+    incoming: VecDeque<String>,
+    sent: Arc<Mutex<Vec<String>>>,
+}
+
+#[cfg(any(test, feature = "mock"))]
+impl ScriptedTransport {
+    /// Creates a transport that receives the given messages, in order.
+    pub fn new(messages: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            incoming: messages.into_iter().map(Into::into).collect(),
+            sent: Arc::default(),
+        }
+    }
+
+    /// Gets the messages sent by the client so far. The handle stays usable
+    /// after the transport is given to a client.
+    pub fn sent(&self) -> Arc<Mutex<Vec<String>>> {
+        self.sent.clone()
+    }
+}
+
+#[cfg(any(test, feature = "mock"))]
+impl Transport for ScriptedTransport {
+    async fn send(&mut self, text: String) -> crate::Result<()> {
+        self.sent.lock().unwrap().push(text);
+        Ok(())
+    }
+
+    async fn recv(&mut self) -> crate::Result<Option<String>> {
+        Ok(self.incoming.pop_front())
+    }
+
+    async fn close(&mut self) -> crate::Result<()> {
+        self.incoming.clear();
+        Ok(())
+    }
+}
+
+/// Builds a [`TransferClient`](crate::TransferClient), gathering every
+/// connection setting in one place.
+///
+/// ```no_run
+/// # async fn connect() -> doppler_ws::Result<()> {
+/// use std::time::Duration;
+/// use doppler_ws::TransferClientBuilder;
+///
+/// let mut client = TransferClientBuilder::default()
+///     .pairing_timeout(Duration::from_secs(120))
+///     .http_timeout(Duration::from_secs(10))
+///     .connect()
+///     .await?;
+/// println!("Pairing code: {}", client.code());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TransferClientBuilder {
+    pub(crate) options: ConnectOptions,
+    pub(crate) pairing_timeout: Option<Duration>,
+    pub(crate) http_timeout: Option<Duration>,
+    pub(crate) http_version: HttpVersion,
+    pub(crate) domain: String,
+    pub(crate) max_queued: usize,
+    pub(crate) accept_invalid_device_certs: bool,
+    pub(crate) connect_backoff: Backoff,
+    client: Option<reqwest::Client>,
+}
+
+impl Default for TransferClientBuilder {
+    fn default() -> Self {
+        Self::from(ConnectOptions::default())
+    }
+}
+
+impl From<ConnectOptions> for TransferClientBuilder {
+    fn from(options: ConnectOptions) -> Self {
+        Self {
+            options,
+            pairing_timeout: None,
+            http_timeout: None,
+            http_version: HttpVersion::default(),
+            domain: API_DOMAIN.to_string(),
+            max_queued: DEFAULT_MAX_QUEUED,
+            accept_invalid_device_certs: false,
+            connect_backoff: Backoff::default(),
+            client: None,
+        }
+    }
+}
+
+impl TransferClientBuilder {
+    /// Sets how long to wait for each message from the pairing API, such as
+    /// the pairing code or a device responding to it. Defaults to waiting
+    /// forever.
+    ///
+    /// Waiting for a new device to pair is included, so this should allow
+    /// time for the user to enter the code.
+    pub fn pairing_timeout(mut self, timeout: Duration) -> Self {
+        self.pairing_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long connecting to, or waiting on a read from, the API and
+    /// paired devices may take. Defaults to no limit.
+    ///
+    /// This doesn't limit the total time of a request, so large uploads
+    /// aren't cut off.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the HTTP version used with paired devices. Defaults to
+    /// [`HttpVersion::Http1`].
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.http_version = version;
+        self
+    }
+
+    /// Sets whether to accept any certificate from paired devices that serve
+    /// their LAN URL over HTTPS, such as a self-signed one. Defaults to
+    /// false. The pairing API's certificate is always checked.
+    ///
+    /// See [`DeviceClientBuilder::accept_invalid_certs`] for the risks.
+    ///
+    /// [`DeviceClientBuilder::accept_invalid_certs`]:
+    ///     crate::device::DeviceClientBuilder::accept_invalid_certs
+    pub fn accept_invalid_device_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_device_certs = accept;
+        self
+    }
+
+    /// Sets the domain of the pairing API, such as for a test server.
+    /// Defaults to `doppler-transfer.com`.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = domain.into();
+        self
+    }
+
+    /// Sets how many messages from the API are kept while waiting for a
+    /// different kind, such as devices that arrive before the pairing code.
+    /// Defaults to 256.
+    ///
+    /// Once the limit is reached, the oldest message is dropped with a
+    /// warning, so a misbehaving server can't use up memory.
+    pub fn max_queued_messages(mut self, max: usize) -> Self {
+        self.max_queued = max;
+        self
+    }
+
+    /// Sets the HTTP client used for requests to the pairing API.
+    ///
+    /// The client is used as is, so the proxy and HTTP timeout aren't
+    /// applied to it.
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Sets an HTTP proxy to tunnel connections through.
+    ///
+    /// See [`ConnectOptions::proxy`].
+    pub fn proxy(mut self, proxy: url::Url) -> Self {
+        self.options.proxy = Some(proxy);
+        self
+    }
+
+    /// Sets the TLS connector used for the websocket.
+    ///
+    /// See [`ConnectOptions::tls_connector`].
+    pub fn tls_connector(mut self, connector: Connector) -> Self {
+        self.options.tls_connector = Some(connector);
+        self
+    }
+
+    /// Sets the ID identifying this client to the API.
+    ///
+    /// See [`ConnectOptions::client_id`].
+    pub fn client_id(mut self, id: Uuid) -> Self {
+        self.options.client_id = Some(id);
+        self
+    }
+
+    /// Sets how many times to retry connecting after a transient error.
+    ///
+    /// See [`ConnectOptions::connect_retries`].
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.options.connect_retries = retries;
+        self
+    }
+
+    /// Sets how long to wait before each retry of connecting. Defaults to
+    /// [`Backoff::default`], waiting 500ms and doubling with each retry.
+    pub fn connect_backoff(mut self, backoff: Backoff) -> Self {
+        self.connect_backoff = backoff;
+        self
+    }
+
+    /// Sets the User-Agent sent to the pairing API and paired devices, such
+    /// as the name and version of the app using this crate.
+    ///
+    /// See [`ConnectOptions::user_agent`].
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.options.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Connects to the Doppler Transfer API.
+    pub async fn connect(self) -> crate::Result<crate::TransferClient> {
+        crate::TransferClient::from_builder(self).await
+    }
+
+    /// Starts a client that reaches the pairing API over `transport` rather
+    /// than a websocket, waiting for it to send the pairing code.
+    ///
+    /// The connection settings, such as the proxy, don't apply to the
+    /// transport. [`TransferClient::refresh_code`] isn't available, as it
+    /// relies on reconnecting.
+    ///
+    /// [`TransferClient::refresh_code`]: crate::TransferClient::refresh_code
+    pub async fn connect_over<T: Transport>(
+        self,
+        transport: T,
+    ) -> crate::Result<crate::TransferClient<T>> {
+        crate::TransferClient::from_transport(transport, self).await
+    }
+
+    /// Connects to the Doppler Transfer API, returning a blocking client.
+    #[cfg(feature = "blocking")]
+    pub fn connect_blocking(self) -> crate::Result<crate::blocking::TransferClient> {
+        crate::blocking::TransferClient::from_builder(self)
+    }
+
+    /// Takes the HTTP client used alongside the websocket, creating one if
+    /// none was given.
+    pub(crate) fn take_http_client(&mut self) -> crate::Result<reqwest::Client> {
+        if let Some(client) = self.client.take() {
+            return Ok(client);
+        }
+        let mut builder =
+            reqwest::Client::builder().user_agent(user_agent(self.options.user_agent.clone()));
+        if let Some(proxy) = &self.options.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy.as_str())?);
+        }
+        if let Some(timeout) = self.http_timeout {
+            builder = builder.connect_timeout(timeout).read_timeout(timeout);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// The websocket stream used for pairing.
+pub(crate) type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Default number of messages kept while waiting for another kind.
+const DEFAULT_MAX_QUEUED: usize = 256;
+
+/// Gets the User-Agent to send, falling back to [`USER_AGENT`](crate::USER_AGENT).
+pub(crate) fn user_agent(user_agent: Option<HeaderValue>) -> HeaderValue {
+    user_agent.unwrap_or(HeaderValue::from_static(crate::USER_AGENT))
+}
+
+/// Upper limit on the size of a proxy's `CONNECT` response headers.
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+/// Options for connecting to the pairing API.
+#[derive(Debug, Default)]
+pub struct ConnectOptions {
+    /// An HTTP proxy to tunnel connections through, such as
+    /// `http://proxy.example:3128`. The websocket is tunneled with `CONNECT`,
+    /// and the HTTP client used for push requests is configured to use it too.
+    ///
+    /// Proxy authentication isn't currently supported.
+    pub proxy: Option<url::Url>,
+    /// The TLS connector used for the websocket, such as one with a custom
+    /// root store. If not set, the webpki roots are used.
+    pub tls_connector: Option<Connector>,
+    /// The ID identifying this client to the API. If not set, a random ID is
+    /// generated for each connection.
+    pub client_id: Option<Uuid>,
+    /// How many times to retry connecting after a transient error, such as a
+    /// DNS failure or dropped connection. Defaults to no retries.
+    pub connect_retries: u32,
+    /// The User-Agent sent to the pairing API, on both the websocket
+    /// handshake and push requests, and to paired devices. If not set,
+    /// [`USER_AGENT`](crate::USER_AGENT) is sent.
+    pub user_agent: Option<HeaderValue>,
+}
+
+impl ConnectOptions {
+    /// Opens a websocket to the given URI.
+    pub(crate) async fn open_websocket(&self, uri: http::Uri) -> crate::Result<WsStream> {
+        let mut builder = ClientBuilder::from_uri(uri.clone()).add_header(
+            http::header::USER_AGENT,
+            user_agent(self.user_agent.clone()),
+        );
+        if let Some(connector) = &self.tls_connector {
+            builder = builder.connector(connector);
+        }
+
+        let Some(proxy) = &self.proxy else {
+            let (ws_client, _) = builder.connect().await?;
+            return Ok(ws_client);
+        };
+
+        let host = uri
+            .host()
+            .ok_or(ApiError::InvalidUrl(url::ParseError::EmptyHost))?;
+        let stream = proxy_tunnel(proxy, host, 443).await?;
+        let default_connector;
+        let connector = match &self.tls_connector {
+            Some(connector) => connector,
+            None => {
+                default_connector = Connector::new()?;
+                &default_connector
+            }
+        };
+        let stream = connector.wrap(host, stream).await?;
+        let (ws_client, _) = builder.connect_on(stream).await?;
+        Ok(ws_client)
+    }
+}
+
+/// Opens a tunnel to `host:port` through an HTTP proxy using `CONNECT`.
+async fn proxy_tunnel(proxy: &url::Url, host: &str, port: u16) -> crate::Result<TcpStream> {
+    let proxy_host = proxy
+        .host_str()
+        .ok_or(ApiError::InvalidUrl(url::ParseError::EmptyHost))?;
+    let proxy_port = proxy
+        .port_or_known_default()
+        .ok_or(ApiError::InvalidUrl(url::ParseError::InvalidPort))?;
+    let mut stream = TcpStream::connect((proxy_host, proxy_port)).await?;
+
+    let request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n\r\n");
+    stream.write_all(request.as_bytes()).await?;
+
+    // Read a byte at a time so nothing past the headers is consumed, as that
+    // belongs to the tunneled connection
+    let mut response = Vec::new();
+    while !response.ends_with(b"\r\n\r\n") {
+        if response.len() >= MAX_PROXY_RESPONSE {
+            return Err(ApiError::MalformedResponse);
+        }
+        response.push(stream.read_u8().await?);
+    }
+
+    // Status line looks like "HTTP/1.1 200 Connection established"
+    let status = std::str::from_utf8(&response)
+        .ok()
+        .and_then(|r| r.split_whitespace().nth(1))
+        .and_then(|code| http::StatusCode::from_bytes(code.as_bytes()).ok())
+        .ok_or(ApiError::MalformedResponse)?;
+    if status.is_success() {
+        Ok(stream)
+    } else {
+        Err(ApiError::BadResponse(status))
+    }
+}
@@ -1,45 +1,877 @@
-use std::path::Path;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::{Ipv6Addr, SocketAddr, SocketAddrV6},
+    path::Path,
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use futures_util::{
+    future::{self, Either},
+    TryStreamExt,
+};
+use http::HeaderValue;
+use http_body_util::BodyStream;
 use mime::Mime;
 use reqwest::multipart;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::io::AsyncRead;
+use tokio_util::{io::ReaderStream, sync::CancellationToken};
+use unicode_normalization::UnicodeNormalization;
 
-use crate::{error::ApiError, model};
+use crate::{connection, error::ApiError, model};
+
+/// Stands in for the host of a LAN URL with an IPv6 zone, which URLs can't
+/// hold. It's resolved to the scoped address when connecting.
+const SCOPED_HOST: &str = "doppler-device.invalid";
+
+/// How long to wait for the device to answer a [`DeviceClient::ping`].
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for the device to answer over HTTP/2 before falling back
+/// to HTTP/1.1.
+const HTTP2_PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The `app_version`s reported by devices that this crate has been tested
+/// with.
+///
+/// None are known yet. The app doesn't document what its version number
+/// means or when upload behavior changes, and no release has been checked
+/// against this crate. Versions should be added as they're confirmed to work.
+pub const TESTED_APP_VERSIONS: &[u32] = &[];
+
+/// Default name of the multipart field holding the uploaded file's name, as
+/// used by the Wi-Fi Transfer website.
+pub const DEFAULT_FILENAME_FIELD: &str = "filename";
+
+/// Default name of the multipart field holding the uploaded file's data, as
+/// used by the Wi-Fi Transfer website.
+pub const DEFAULT_FILE_FIELD: &str = "file";
+
+/// Most files sent in one request by [`DeviceClient::upload_batch`].
+#[cfg(feature = "batch")]
+pub const MAX_BATCH_FILES: usize = 100;
+
+/// Name of the multipart field naming the playlist to add an upload to.
+///
+/// The Wi-Fi Transfer website never sends one, so this is a guess, and the
+/// device may ignore it.
+const PLAYLIST_FIELD: &str = "playlist";
+
+/// Size of the chunks read from disk by [`DeviceClient::upload_file`].
+pub const UPLOAD_CHUNK_SIZE: usize = 64 * 1024;
+
+/// MIME types Doppler is commonly known to accept, for checking files without
+/// a paired device.
+///
+/// This list isn't reported by the API, so it may be incomplete or out of
+/// date. When a device is available, [`DeviceClient::mime_supported`] should
+/// be used instead.
+pub const COMMON_MIMETYPES: &[&str] = &[
+    "audio/aac",
+    "audio/aiff",
+    "audio/flac",
+    "audio/mp4",
+    "audio/mpeg",
+    "audio/ogg",
+    "audio/opus",
+    "audio/wav",
+    "audio/x-m4a",
+];
+
+/// Checks whether the given `Mime` is in [`COMMON_MIMETYPES`], falling back
+/// to the `x-` prefixed type like [`DeviceClient::mime_supported`].
+pub fn mime_commonly_supported(mime: &Mime) -> bool {
+    mime_in_list(COMMON_MIMETYPES, mime, false)
+}
+
+/// Statuses the device is taken to send while another Wi-Fi Transfer session
+/// is using it.
+///
+/// The response to a second session hasn't been captured, so these are the
+/// statuses an HTTP server would use to turn a client away because of
+/// another: `409 Conflict` and `423 Locked`. Any other unsuccessful status
+/// is reported as [`ApiError::BadResponse`], and is worth reporting if it's
+/// seen while the device is busy.
+const BUSY_STATUSES: [reqwest::StatusCode; 2] =
+    [reqwest::StatusCode::CONFLICT, reqwest::StatusCode::LOCKED];
+
+/// Statuses the device is taken to send once the session from pairing has
+/// expired. These are inferred from what HTTP servers usually send for an
+/// expired session; the device hasn't been seen sending them.
+const EXPIRED_STATUSES: [reqwest::StatusCode; 2] = [
+    reqwest::StatusCode::UNAUTHORIZED,
+    reqwest::StatusCode::FORBIDDEN,
+];
+
+/// Turns an unsuccessful response from the device into an error.
+///
+/// Neither a busy device nor an expired session has been seen, so
+/// [`ApiError::DeviceBusy`] and [`ApiError::SessionExpired`] are detected
+/// from the statuses in [`BUSY_STATUSES`] and [`EXPIRED_STATUSES`], which
+/// are guesses. Any other status is reported as [`ApiError::BadResponse`].
+fn check_status(response: reqwest::Response) -> crate::Result<reqwest::Response> {
+    let status = response.status();
+    if status.is_success() {
+        Ok(response)
+    } else if BUSY_STATUSES.contains(&status) {
+        Err(ApiError::DeviceBusy)
+    } else if EXPIRED_STATUSES.contains(&status) {
+        Err(ApiError::SessionExpired)
+    } else {
+        Err(ApiError::BadResponse(status))
+    }
+}
+
+/// Requests the device's info from its LAN URL, returning it both parsed and
+/// as sent.
+async fn fetch_info(
+    client: &reqwest::Client,
+    base_uri: &reqwest::Url,
+) -> crate::Result<(model::DeviceInfo, serde_json::Value)> {
+    let response = client.get(base_uri.join("info").unwrap()).send().await?;
+    let raw: serde_json::Value = check_status(response)?.json().await?;
+    Ok((model::DeviceInfo::deserialize(&raw)?, raw))
+}
+
+/// Checks whether `mime` is in `list`, also trying the `x-` prefixed type
+/// unless `strict` is set. Only the essence of the type is compared.
+fn mime_in_list(list: &[impl AsRef<str>], mime: &Mime, strict: bool) -> bool {
+    let x_mime = format!("{}/x-{}", mime.type_(), mime.subtype());
+    list.iter()
+        .map(AsRef::as_ref)
+        .any(|mt| mt == mime.essence_str() || (!strict && mt == x_mime))
+}
+
+/// The HTTP version used to talk to the device.
+///
+/// Connections are kept alive and reused across uploads with either version.
+/// HTTP/2 also lets concurrent uploads share a single connection.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// Always use HTTP/1.1.
+    #[default]
+    Http1,
+    /// Use HTTP/2 if the device supports it, otherwise HTTP/1.1.
+    ///
+    /// The device is reached over plain HTTP, where the version can't be
+    /// negotiated, so HTTP/2 is tried when first requesting the device info,
+    /// and HTTP/1.1 is used if that fails. No device has been seen speaking
+    /// HTTP/2, and trying it can add up to two seconds to connecting.
+    Auto,
+}
+
+/// A device's LAN URL, as reported when pairing.
+///
+/// Devices on IPv6-only networks may report a link-local address with a zone
+/// naming the network interface, such as `http://[fe80::1%en0]:8080/`. URLs
+/// can't hold a zone, so the host is replaced with a placeholder, and
+/// connections to it go to [`LanUrl::scoped_addr`] instead.
+///
+/// ```
+/// use doppler_ws::device::LanUrl;
+///
+/// let url = LanUrl::parse("http://192.168.1.20:8080/")?;
+/// assert_eq!(url.url().as_str(), "http://192.168.1.20:8080/");
+/// assert_eq!(url.scoped_addr(), None);
+/// assert_eq!(url.address(), "192.168.1.20:8080");
+///
+/// // Both the raw zone and the RFC 6874 form with an escaped '%' work
+/// for lan_url in ["http://[fe80::1%5]:8080/", "http://[fe80::1%255]:8080/"] {
+///     let url = LanUrl::parse(lan_url)?;
+///     let addr = url.scoped_addr().unwrap();
+///     assert_eq!(addr.ip().to_string(), "fe80::1");
+///     assert_eq!(addr.port(), 8080);
+///     assert_eq!(addr.scope_id(), 5);
+///     assert_eq!(url.address(), "[fe80::1%5]:8080");
+/// }
+/// # Ok::<(), doppler_ws::error::ApiError>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct LanUrl {
+    url: reqwest::Url,
+    scoped_addr: Option<SocketAddrV6>,
+}
+
+impl LanUrl {
+    /// Parses a LAN URL. A zone may be given as an interface index, or on
+    /// Unix as an interface name.
+    ///
+    /// The zone may also follow the `%25` escape from RFC 6874, which can't
+    /// always be told apart from a raw zone starting with `25`. The escaped
+    /// reading is taken whenever what follows the `25` is an index or an
+    /// existing interface, so `%255` is index 5 rather than 255, while
+    /// `%25abc` is interface `25abc` if there's no interface `abc`.
+    pub fn parse(uri: &str) -> crate::Result<Self> {
+        let Some((start, end, ip, zone)) = split_zone(uri) else {
+            return Ok(Self {
+                url: reqwest::Url::parse(uri)?,
+                scoped_addr: None,
+            });
+        };
+
+        let ip: Ipv6Addr = ip
+            .parse()
+            .map_err(|_| url::ParseError::InvalidIpv6Address)?;
+        // RFC 6874 escapes the '%' before the zone as "%25"
+        let escaped = zone.strip_prefix("25").filter(|rest| !rest.is_empty());
+        let scope_id = escaped
+            .and_then(scope_id)
+            .or_else(|| scope_id(zone))
+            .ok_or_else(|| ApiError::UnknownInterface(escaped.unwrap_or(zone).into()))?;
+
+        let url = reqwest::Url::parse(&format!("{}{SCOPED_HOST}{}", &uri[..start], &uri[end..]))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        Ok(Self {
+            url,
+            scoped_addr: Some(SocketAddrV6::new(ip, port, 0, scope_id)),
+        })
+    }
+
+    /// Gets the URL to send requests to. For a scoped address, the host is a
+    /// placeholder.
+    pub fn url(&self) -> &reqwest::Url {
+        &self.url
+    }
+
+    /// Gets the address a URL with an IPv6 zone connects to, including the
+    /// zone as its scope ID.
+    pub fn scoped_addr(&self) -> Option<SocketAddrV6> {
+        self.scoped_addr
+    }
+
+    /// Gets the host and port the URL connects to, such as
+    /// `192.168.1.20:8080`, for telling devices apart in messages.
+    pub fn address(&self) -> String {
+        if let Some(addr) = self.scoped_addr {
+            return addr.to_string();
+        }
+        match (self.url.host_str(), self.url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{host}:{port}"),
+            _ => self.url.to_string(),
+        }
+    }
+}
+
+/// Finds a bracketed IPv6 host with a zone in a URL's authority, returning
+/// the byte range of the brackets, the address and the zone.
+fn split_zone(uri: &str) -> Option<(usize, usize, &str, &str)> {
+    let authority_start = uri.find("://")? + 3;
+    let authority_len = uri[authority_start..]
+        .find(['/', '?', '#'])
+        .unwrap_or(uri.len() - authority_start);
+    let authority = &uri[authority_start..authority_start + authority_len];
+    let start = authority_start + authority.find('[')?;
+    let end = start + uri[start..authority_start + authority_len].find(']')? + 1;
+    let (ip, zone) = uri[start + 1..end - 1].split_once('%')?;
+    Some((start, end, ip, zone))
+}
+
+/// Gets the scope ID for a zone, given as an interface index or name.
+fn scope_id(zone: &str) -> Option<u32> {
+    zone.parse().ok().or_else(|| interface_index(zone))
+}
+
+/// Looks up the index of a network interface by name.
+#[cfg(unix)]
+fn interface_index(name: &str) -> Option<u32> {
+    nix::net::if_::if_nametoindex(name).ok()
+}
+
+/// Looks up the index of a network interface by name. Only indexes can be
+/// used as zones here.
+#[cfg(not(unix))]
+fn interface_index(_name: &str) -> Option<u32> {
+    None
+}
+
+/// The result of a successful upload.
+#[derive(Clone, Debug)]
+pub struct UploadOutcome {
+    /// SHA-256 of the data sent to the device, as lowercase hex.
+    pub sha256: String,
+    /// Checksum of the received file, if the device reported one. Current
+    /// versions of the app don't appear to, so this will usually be `None`.
+    pub device_sha256: Option<String>,
+}
+
+/// The result of a completed pairing.
+pub struct PairedDevice {
+    /// The connection to the device.
+    pub client: DeviceClient,
+    /// Whether the device asked to be saved. If so, the record to save is
+    /// available from [`DeviceClient::push_token`], and can be used to pair
+    /// again later with [`TransferClient::get_saved_device`].
+    ///
+    /// [`TransferClient::get_saved_device`]: crate::TransferClient::get_saved_device
+    pub save_requested: bool,
+}
+
+/// How file names are Unicode-normalized before being sent to the device.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum NameNormalization {
+    /// Send names as they are.
+    #[default]
+    None,
+    /// Normalization Form C (composed), as used by most systems.
+    Nfc,
+    /// Normalization Form D (decomposed), as used by some macOS file systems.
+    Nfd,
+}
+
+impl NameNormalization {
+    fn apply(self, name: String) -> String {
+        match self {
+            Self::None => name,
+            Self::Nfc => name.nfc().collect(),
+            Self::Nfd => name.nfd().collect(),
+        }
+    }
+}
+
+/// Uploading files to a device.
+///
+/// This is implemented by [`DeviceClient`], and by `MockDevice` with the
+/// `mock` feature. Code that only needs to upload can be written against this
+/// trait, so a stand-in can replace the device in tests.
+pub trait Upload {
+    /// Uploads the file at `path` to the device.
+    fn upload_file(
+        &self,
+        path: &Path,
+        mime: Mime,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send;
+
+    /// Uploads the file at `path` to the device, calling `on_progress` with
+    /// the number of bytes in each chunk as it's sent.
+    ///
+    /// By default, this uploads with [`Upload::upload_file`] and never
+    /// reports progress.
+    fn upload_file_with_progress(
+        &self,
+        path: &Path,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        let _ = on_progress;
+        self.upload_file(path, mime)
+    }
+
+    /// Uploads the file at `path` to the device under the file name `name`,
+    /// reporting progress like [`Upload::upload_file_with_progress`].
+    fn upload_file_as(
+        &self,
+        path: &Path,
+        name: &str,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send;
+}
+
+impl Upload for DeviceClient {
+    fn upload_file(
+        &self,
+        path: &Path,
+        mime: Mime,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        DeviceClient::upload_file(self, path, mime)
+    }
+
+    fn upload_file_with_progress(
+        &self,
+        path: &Path,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        DeviceClient::upload_file_with_progress(self, path, mime, on_progress)
+    }
+
+    fn upload_file_as(
+        &self,
+        path: &Path,
+        name: &str,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        DeviceClient::upload_file_as(self, path, name, mime, on_progress)
+    }
+}
+
+/// A device that doesn't exist, for testing code written against [`Upload`].
+///
+/// Uploads read and hash the file without sending it anywhere, reporting
+/// progress for each chunk read.
+///
+/// ```
+/// use doppler_ws::{device::{MockDevice, Upload}, model::DeviceInfo};
+///
+/// # async fn run() -> doppler_ws::Result<()> {
+/// let device = MockDevice::new(DeviceInfo {
+///     device_name: "Phone".to_string(),
+///     known_file_extensions: vec!["mp3".to_string()],
+///     supported_mimetypes: vec!["audio/mpeg".to_string()],
+///     app_name: "Doppler".to_string(),
+///     app_version: 1,
+/// });
+/// let mime = "audio/mpeg".parse().unwrap();
+/// let outcome = device.upload_file("song.mp3".as_ref(), mime).await?;
+/// println!("{} would have received {}", device.info().device_name, outcome.sha256);
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "mock")]
+#[derive(Clone, Debug)]
+pub struct MockDevice {
+    info: model::DeviceInfo,
+}
+
+#[cfg(feature = "mock")]
+impl MockDevice {
+    /// Creates a mock device that reports the given `info`.
+    pub fn new(info: model::DeviceInfo) -> Self {
+        Self { info }
+    }
+
+    /// Gets the info the device was created with.
+    pub fn info(&self) -> &model::DeviceInfo {
+        &self.info
+    }
+
+    async fn read_file(
+        &self,
+        path: &Path,
+        on_progress: Option<ProgressFn>,
+    ) -> crate::Result<UploadOutcome> {
+        let file = tokio::fs::File::open(path).await?;
+        let mut chunks = ReaderStream::with_capacity(file, UPLOAD_CHUNK_SIZE);
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = chunks.try_next().await? {
+            hasher.update(&chunk);
+            if let Some(on_progress) = &on_progress {
+                on_progress(chunk.len() as u64);
+            }
+        }
+        Ok(UploadOutcome {
+            sha256: format!("{:x}", hasher.finalize()),
+            device_sha256: None,
+        })
+    }
+}
+
+#[cfg(feature = "mock")]
+impl Upload for MockDevice {
+    fn upload_file(
+        &self,
+        path: &Path,
+        mime: Mime,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        let _ = mime;
+        self.read_file(path, None)
+    }
+
+    fn upload_file_with_progress(
+        &self,
+        path: &Path,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        let _ = mime;
+        self.read_file(path, Some(on_progress))
+    }
+
+    fn upload_file_as(
+        &self,
+        path: &Path,
+        name: &str,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> impl Future<Output = crate::Result<UploadOutcome>> + Send {
+        let _ = (name, mime);
+        self.read_file(path, Some(on_progress))
+    }
+}
+
+/// A callback for upload progress, given the number of bytes just sent.
+pub type ProgressFn = Arc<dyn Fn(u64) + Send + Sync>;
+
+/// Limits how often a [`ProgressFn`] is called during an upload.
+///
+/// The callback is only called once at least `bytes` have been sent and
+/// `interval` has passed since it was last called, and is given every byte
+/// sent since. Whatever is left is reported once the upload has been sent, so
+/// the callback is still given the whole size.
+///
+/// The default calls it for every chunk sent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProgressThrottle {
+    /// The fewest bytes to report at once.
+    pub bytes: u64,
+    /// The shortest time between calls.
+    pub interval: Duration,
+}
+
+/// Reports an upload's progress no more often than its throttle allows,
+/// holding back the bytes sent in between.
+struct ThrottledProgress {
+    on_progress: ProgressFn,
+    throttle: ProgressThrottle,
+    /// Bytes sent but not yet reported.
+    pending: u64,
+    /// When progress was last reported, or the upload started.
+    last: Instant,
+}
+
+impl ThrottledProgress {
+    fn new(on_progress: ProgressFn, throttle: ProgressThrottle) -> Self {
+        Self {
+            on_progress,
+            throttle,
+            pending: 0,
+            last: Instant::now(),
+        }
+    }
+
+    /// Counts bytes that were just sent, reporting them if it's time.
+    fn sent(&mut self, bytes: u64) {
+        self.pending += bytes;
+        if self.pending >= self.throttle.bytes && self.last.elapsed() >= self.throttle.interval {
+            self.flush();
+        }
+    }
+
+    /// Reports the bytes held back, if any.
+    fn flush(&mut self) {
+        if self.pending > 0 {
+            (self.on_progress)(std::mem::take(&mut self.pending));
+            self.last = Instant::now();
+        }
+    }
+}
+
+/// Optional parts sent along with an upload's file name and data.
+#[derive(Default)]
+struct ExtraParts<'a> {
+    artwork: Option<(Mime, reqwest::Body)>,
+    playlist: Option<&'a str>,
+}
 
 /// A connection to a Doppler device.
 pub struct DeviceClient {
     http_client: reqwest::Client,
     info: model::DeviceInfo,
+    /// The info as the device sent it, including fields `info` doesn't have.
+    info_raw: serde_json::Value,
     base_uri: reqwest::Url,
+    /// Where the device is reached, from [`LanUrl::address`].
+    address: String,
     push_token: Option<model::Device>,
+    verify_checksum: bool,
+    name_normalization: NameNormalization,
+    filename_field: String,
+    file_field: String,
+    /// Playlist to add every upload to, if any.
+    playlist: Option<String>,
+    progress_throttle: ProgressThrottle,
 }
 
-impl DeviceClient {
-    /// Creates a new DeviceClient from the given LAN URL.
-    pub(crate) async fn new(
-        uri: impl AsRef<str>,
-        push_token: Option<model::Device>,
-    ) -> crate::Result<Self> {
-        let base_uri = reqwest::Url::parse(uri.as_ref())?;
-        let http_client = reqwest::Client::new();
-        let info: model::DeviceInfo = http_client
-            .get(base_uri.join("info").unwrap())
-            .send()
-            .await?
-            .json()
-            .await?;
-        Ok(Self {
+/// Builds a [`DeviceClient`] for the device at a LAN URL.
+///
+/// This is normally done by [`TransferClient::confirm`], but can be used to
+/// reconnect to a device at the URL it was last paired at, skipping the
+/// pairing API. The device appears to only serve uploads while the app's
+/// Wi-Fi transfer screen is open, and its URL may have changed, so this
+/// should be expected to fail.
+///
+/// ```no_run
+/// # async fn reconnect() -> doppler_ws::Result<()> {
+/// use std::time::Duration;
+/// use doppler_ws::device::DeviceClientBuilder;
+///
+/// let device = DeviceClientBuilder::new("http://192.168.1.20:8080/")
+///     .http_timeout(Duration::from_secs(10))
+///     .connect()
+///     .await?;
+/// println!("Connected to {}", device.device_name());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [`TransferClient::confirm`]: crate::TransferClient::confirm
+#[derive(Debug)]
+pub struct DeviceClientBuilder {
+    uri: String,
+    push_token: Option<model::Device>,
+    http_timeout: Option<Duration>,
+    http_version: HttpVersion,
+    user_agent: Option<HeaderValue>,
+    accept_invalid_certs: bool,
+}
+
+impl DeviceClientBuilder {
+    /// Starts building a client for the device at the LAN URL `uri`.
+    pub fn new(uri: impl Into<String>) -> Self {
+        Self {
+            uri: uri.into(),
+            push_token: None,
+            http_timeout: None,
+            http_version: HttpVersion::default(),
+            user_agent: None,
+            accept_invalid_certs: false,
+        }
+    }
+
+    /// Sets the push token the device was paired with, kept for pairing with
+    /// it again.
+    pub fn push_token(mut self, push_token: model::Device) -> Self {
+        self.push_token = Some(push_token);
+        self
+    }
+
+    /// Sets how long connecting or waiting on a read may take. Defaults to no
+    /// limit.
+    ///
+    /// This applies to every request the client makes, not just reaching the
+    /// device, and doesn't limit the total time of a request.
+    pub fn http_timeout(mut self, timeout: Duration) -> Self {
+        self.http_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the HTTP version used with the device. Defaults to
+    /// [`HttpVersion::Http1`].
+    pub fn http_version(mut self, version: HttpVersion) -> Self {
+        self.http_version = version;
+        self
+    }
+
+    /// Sets the User-Agent requests are sent with. Defaults to
+    /// [`USER_AGENT`](crate::USER_AGENT).
+    pub fn user_agent(mut self, user_agent: HeaderValue) -> Self {
+        self.user_agent = Some(user_agent);
+        self
+    }
+
+    /// Sets whether to trust a device serving its LAN URL over HTTPS whatever
+    /// certificate it presents, such as a self-signed one. Defaults to false.
+    ///
+    /// This gives up protection against another machine on the network posing
+    /// as the device, so only set it for devices that need it.
+    pub fn accept_invalid_certs(mut self, accept: bool) -> Self {
+        self.accept_invalid_certs = accept;
+        self
+    }
+
+    /// Connects to the device, fetching its info.
+    pub async fn connect(self) -> crate::Result<DeviceClient> {
+        let lan_url = LanUrl::parse(&self.uri)?;
+        let base_uri = lan_url.url().clone();
+        let user_agent = connection::user_agent(self.user_agent);
+        let build_client = |http2: bool| {
+            let mut builder = reqwest::Client::builder()
+                .user_agent(user_agent.clone())
+                .danger_accept_invalid_certs(self.accept_invalid_certs);
+            if let Some(addr) = lan_url.scoped_addr() {
+                builder = builder.resolve(SCOPED_HOST, SocketAddr::V6(addr));
+            }
+            if let Some(timeout) = self.http_timeout {
+                builder = builder.connect_timeout(timeout).read_timeout(timeout);
+            }
+            builder = if http2 {
+                builder.http2_prior_knowledge()
+            } else {
+                builder.http1_only()
+            };
+            builder.build()
+        };
+
+        let mut http2 = None;
+        if self.http_version == HttpVersion::Auto {
+            let client = build_client(true)?;
+            let probe = tokio::time::timeout(HTTP2_PROBE_TIMEOUT, fetch_info(&client, &base_uri));
+            match probe.await {
+                Ok(Ok(info)) => {
+                    tracing::debug!("Using HTTP/2 with the device");
+                    http2 = Some((client, info));
+                }
+                Ok(Err(err)) => tracing::debug!("HTTP/2 failed, using HTTP/1.1: {err}"),
+                Err(_) => tracing::debug!("HTTP/2 timed out, using HTTP/1.1"),
+            }
+        }
+        let (http_client, (info, info_raw)) = match http2 {
+            Some(found) => found,
+            None => {
+                let client = build_client(false)?;
+                let info = fetch_info(&client, &base_uri).await?;
+                (client, info)
+            }
+        };
+
+        Ok(DeviceClient {
             http_client,
             info,
+            info_raw,
             base_uri,
-            push_token,
+            address: lan_url.address(),
+            push_token: self.push_token,
+            verify_checksum: false,
+            name_normalization: NameNormalization::None,
+            filename_field: DEFAULT_FILENAME_FIELD.to_string(),
+            file_field: DEFAULT_FILE_FIELD.to_string(),
+            playlist: None,
+            progress_throttle: ProgressThrottle::default(),
         })
     }
+}
+
+impl DeviceClient {
+    /// Sets whether uploads should fail with [`ApiError::ChecksumMismatch`]
+    /// when the device reports a checksum that differs from the data sent.
+    ///
+    /// Disabled by default. Uploads are never checked if the device doesn't
+    /// report a checksum.
+    pub fn set_verify_checksum(&mut self, verify: bool) {
+        self.verify_checksum = verify;
+    }
+
+    /// Sets how file names are normalized before uploading.
+    ///
+    /// Names are sent as they are by default. Files named on macOS may use
+    /// decomposed (NFD) names, which can show up mangled on the device, so
+    /// [`NameNormalization::Nfc`] can be used to fix them.
+    pub fn set_name_normalization(&mut self, normalization: NameNormalization) {
+        self.name_normalization = normalization;
+    }
+
+    /// Sets the names of the multipart fields used to upload the file name
+    /// and data.
+    ///
+    /// The defaults, [`DEFAULT_FILENAME_FIELD`] and [`DEFAULT_FILE_FIELD`],
+    /// match what the app accepts today. This allows working around a change
+    /// in the app without waiting for a new release of this crate.
+    pub fn set_upload_field_names(
+        &mut self,
+        filename_field: impl Into<String>,
+        file_field: impl Into<String>,
+    ) {
+        self.filename_field = filename_field.into();
+        self.file_field = file_field.into();
+    }
+
+    /// Sets the playlist every upload is added to, or `None` to not name one.
+    ///
+    /// See [`DeviceClient::upload_to`] for whether the device honors this.
+    #[cfg(feature = "playlists")]
+    pub fn set_playlist(&mut self, playlist: Option<String>) {
+        self.playlist = playlist;
+    }
+
+    /// Sets how often progress callbacks are called during uploads, such as
+    /// to keep a UI from redrawing for every chunk sent.
+    ///
+    /// By default, callbacks are called for every chunk.
+    pub fn set_progress_throttle(&mut self, throttle: ProgressThrottle) {
+        self.progress_throttle = throttle;
+    }
+
+    /// Checks that the device is still reachable by requesting its info.
+    ///
+    /// Returns [`ApiError::DeviceUnreachable`] if the request couldn't be
+    /// completed, such as when the device has left the network, or
+    /// [`ApiError::DeviceBusy`] if another transfer is using it, or
+    /// [`ApiError::SessionExpired`] if it no longer accepts this session.
+    pub async fn ping(&self) -> crate::Result<()> {
+        let response = self
+            .http_client
+            .get(self.base_uri.join("info").unwrap())
+            .timeout(PING_TIMEOUT)
+            .send()
+            .await
+            .map_err(ApiError::DeviceUnreachable)?;
+        check_status(response)?;
+        Ok(())
+    }
+
+    /// Shuts the client down, closing its pooled connections to the device.
+    ///
+    /// Since this takes the client, no requests can still be in flight on it.
+    /// Dropping the client has the same effect; this makes the intent clear
+    /// in long-running sessions.
+    pub fn shutdown(self) {
+        tracing::debug!(uri = %self.base_uri, "Shutting down device client");
+        drop(self.http_client);
+    }
+
+    /// Returns the info the device reported when connecting.
+    pub fn info(&self) -> &model::DeviceInfo {
+        &self.info
+    }
+
+    /// Returns the info the device reported when connecting as the JSON it
+    /// sent, including any fields [`DeviceInfo`](model::DeviceInfo) doesn't
+    /// have yet.
+    ///
+    /// This is for inspecting what newer app versions send. Prefer the typed
+    /// accessors for anything else.
+    pub fn info_raw(&self) -> &serde_json::Value {
+        &self.info_raw
+    }
+
+    /// Returns the name of the device, as set by the user.
+    pub fn device_name(&self) -> &str {
+        &self.info.device_name
+    }
+
+    /// Returns the name of the app running on the device.
+    pub fn app_name(&self) -> &str {
+        &self.info.app_name
+    }
+
+    /// Returns the version of the Doppler app reported by the device.
+    pub fn app_version(&self) -> u32 {
+        self.info.app_version
+    }
+
+    /// Returns whether the device's app version is in
+    /// [`TESTED_APP_VERSIONS`].
+    ///
+    /// No versions have been tested yet, so this is always `false` for now.
+    /// Uploads may well work on an untested version, so this is only meant
+    /// for a warning.
+    pub fn app_version_tested(&self) -> bool {
+        TESTED_APP_VERSIONS.contains(&self.info.app_version)
+    }
+
+    /// Returns how many bytes of storage the device has free, if it reports
+    /// it.
+    ///
+    /// The device's info doesn't include its storage, so this always returns
+    /// `None` for now. It's here so callers can check space once a way to
+    /// read it is found.
+    pub fn free_space(&self) -> Option<u64> {
+        None
+    }
 
     /// Returns a list of all MIME types reported as supported by the device.
     pub fn supported_mimetypes(&self) -> &[String] {
         &self.info.supported_mimetypes
     }
 
+    /// Returns the LAN URL the device is reached at.
+    pub fn lan_url(&self) -> &str {
+        self.base_uri.as_str()
+    }
+
+    /// Returns the host and port the device is reached at, such as
+    /// `192.168.1.42:8080`. Errors from the client don't say which device
+    /// they came from, so this is for adding to them when talking to more
+    /// than one.
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
     /// If the device requested to be saved, provides the device metadata
     /// represented as the "push token" by the Doppler API.
     pub fn push_token(&self) -> Option<&model::Device> {
@@ -48,36 +880,38 @@ impl DeviceClient {
 
     /// Checks whether the given `Mime` is supported by the device.
     ///
+    /// Devices often list the `x-` prefixed form of a type, such as
+    /// `audio/x-flac`, so that form is also accepted. For example,
+    /// `audio/flac` is supported by a device listing only `audio/x-flac`. Use
+    /// [`DeviceClient::mime_supported_strict`] to match exactly.
+    ///
     /// # Examples
     ///
     /// Using [`mime_guess`] with the file path:
     ///
     /// ```no_run
-    /// use tokio::fs::File;
-    ///
+    /// # fn check(client: &doppler_ws::device::DeviceClient) {
     /// let filename = "cool_tapes.mp3";
     ///
     /// // Iterate through all guessed MIME types, checking if any are supported
     /// if mime_guess::from_path(filename)
     ///     .iter()
-    ///     .any(|mime| client.mime_supported(mime))
+    ///     .any(|mime| client.mime_supported(&mime))
     /// {
     ///     // Supported by device!
     /// }
+    /// # }
     /// ```
     pub fn mime_supported(&self, mime: &Mime) -> bool {
-        if self
-            .info
-            .supported_mimetypes
-            .iter()
-            .any(|mt| mt == mime.essence_str())
-        {
-            true
-        } else {
-            // Try with the x- prefixed version of the mimetype
-            let x_mime = format!("{}/x-{}", mime.type_(), mime.subtype());
-            self.info.supported_mimetypes.iter().any(|mt| x_mime.eq(mt))
-        }
+        mime_in_list(&self.info.supported_mimetypes, mime, false)
+    }
+
+    /// Checks whether the given `Mime` is supported by the device, without
+    /// falling back to the `x-` prefixed type.
+    ///
+    /// Only the essence of the type (without parameters) is compared.
+    pub fn mime_supported_strict(&self, mime: &Mime) -> bool {
+        mime_in_list(&self.info.supported_mimetypes, mime, true)
     }
 
     /// Returns a list of all file extensions reported as known by the device.
@@ -85,6 +919,29 @@ impl DeviceClient {
         &self.info.known_file_extensions
     }
 
+    /// Groups the file extensions known by the device for an open-file
+    /// dialog, labeled by kind, such as `("Audio", ["flac", "mp3"])`.
+    ///
+    /// The kind is taken from the MIME type `mime_guess` gives each
+    /// extension, as the device doesn't report one. Extensions it doesn't
+    /// recognize are grouped under "Other".
+    pub fn dialog_filters(&self) -> Vec<(String, Vec<String>)> {
+        let mut groups = BTreeMap::<&str, Vec<String>>::new();
+        for ext in &self.info.known_file_extensions {
+            let label = match mime_guess::from_ext(ext).first() {
+                Some(mime) if mime.type_() == mime::AUDIO => "Audio",
+                Some(mime) if mime.type_() == mime::VIDEO => "Video",
+                Some(mime) if mime.type_() == mime::IMAGE => "Images",
+                _ => "Other",
+            };
+            groups.entry(label).or_default().push(ext.clone());
+        }
+        groups
+            .into_iter()
+            .map(|(label, exts)| (label.to_string(), exts))
+            .collect()
+    }
+
     /// Checks whether the given file path has a supported file extension.
     pub fn extension_supported(&self, path: impl AsRef<Path>) -> bool {
         if let Some(path_ext) = path.as_ref().extension() {
@@ -101,36 +958,486 @@ impl DeviceClient {
     ///
     /// While not enforced by this function, the MIME type and file extension
     /// should be checked before uploading.
+    ///
+    /// The data is hashed as it's sent, and the checksum is returned in the
+    /// [`UploadOutcome`].
+    ///
+    /// The body is streamed to the device a chunk at a time, and nothing is
+    /// buffered beyond the chunk being sent. Memory use per upload is bounded
+    /// by the chunk size of `data` rather than the file size; for a
+    /// [`tokio::fs::File`], that's only a few kilobytes.
+    /// [`DeviceClient::upload_file`] reads in chunks of [`UPLOAD_CHUNK_SIZE`].
+    ///
+    /// Returns [`ApiError::SessionExpired`] if the device turns the upload
+    /// away because the session from pairing has expired. Uploading again
+    /// needs a new client from pairing again.
     pub async fn upload(
         &self,
         filename: impl AsRef<Path>,
         len: u64,
         mime: Mime,
         data: impl Into<reqwest::Body>,
-    ) -> super::Result<()> {
-        let basename = filename
-            .as_ref()
-            .file_name()
-            .ok_or(ApiError::InvalidPath)?
-            .to_string_lossy()
-            .to_string();
-        let form = multipart::Form::new()
-            .part("filename", multipart::Part::text(basename.clone()))
+    ) -> super::Result<UploadOutcome> {
+        self.upload_with_artwork(filename, len, mime, data, None)
+            .await
+    }
+
+    /// Uploads a file to the device like [`DeviceClient::upload`], stopping
+    /// early if `cancel` is triggered.
+    ///
+    /// A canceled upload is abandoned mid-request, and returns
+    /// [`ApiError::Cancelled`]. The device may keep a partial file.
+    pub async fn upload_cancellable(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+        cancel: &CancellationToken,
+    ) -> super::Result<UploadOutcome> {
+        let upload = pin!(self.upload(filename, len, mime, data));
+        match future::select(upload, pin!(cancel.cancelled())).await {
+            Either::Left((result, _)) => result,
+            Either::Right(_) => Err(ApiError::Cancelled),
+        }
+    }
+
+    /// Uploads the file at `path` to the device, reading it in chunks of
+    /// [`UPLOAD_CHUNK_SIZE`].
+    pub async fn upload_file(
+        &self,
+        path: impl AsRef<Path>,
+        mime: Mime,
+    ) -> super::Result<UploadOutcome> {
+        let path = path.as_ref();
+        self.send_file(path, path, mime, None).await
+    }
+
+    /// Uploads the file at `path` to the device like
+    /// [`DeviceClient::upload_file`], calling `on_progress` with the size of
+    /// each chunk as it's sent.
+    pub async fn upload_file_with_progress(
+        &self,
+        path: impl AsRef<Path>,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> super::Result<UploadOutcome> {
+        let path = path.as_ref();
+        self.send_file(path, path, mime, Some(on_progress)).await
+    }
+
+    /// Uploads the file at `path` to the device like
+    /// [`DeviceClient::upload_file_with_progress`], but under the file name
+    /// `name` instead of the file's own name.
+    ///
+    /// Only the last component of `name` is kept, as the device doesn't
+    /// support folders.
+    pub async fn upload_file_as(
+        &self,
+        path: impl AsRef<Path>,
+        name: impl AsRef<Path>,
+        mime: Mime,
+        on_progress: ProgressFn,
+    ) -> super::Result<UploadOutcome> {
+        self.send_file(path.as_ref(), name.as_ref(), mime, Some(on_progress))
+            .await
+    }
+
+    async fn send_file(
+        &self,
+        path: &Path,
+        name: &Path,
+        mime: Mime,
+        on_progress: Option<ProgressFn>,
+    ) -> super::Result<UploadOutcome> {
+        let file = tokio::fs::File::open(path).await?;
+        let len = file.metadata().await?.len();
+        self.send_upload(
+            name,
+            Some(len),
+            mime.as_ref(),
+            reader_body(file),
+            ExtraParts::default(),
+            on_progress,
+        )
+        .await
+    }
+
+    /// Uploads a file to the device, along with optional cover art sent as an
+    /// extra `artwork` part.
+    ///
+    /// It's not known whether the device makes use of the artwork part, and
+    /// it may be ignored. Embedding artwork in the file's tags is currently
+    /// the reliable way to get it onto the device.
+    pub async fn upload_with_artwork(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+        artwork: Option<(Mime, reqwest::Body)>,
+    ) -> super::Result<UploadOutcome> {
+        self.send_upload(
+            filename.as_ref(),
+            Some(len),
+            mime.as_ref(),
+            data.into(),
+            ExtraParts {
+                artwork,
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+    }
+
+    /// Uploads a file to the device like [`DeviceClient::upload`], asking for
+    /// it to be added to an existing playlist.
+    ///
+    /// The playlist is sent as an extra `playlist` part. The upload endpoint
+    /// has only been seen taking the file name and data, so it's not known
+    /// whether the device supports this; it may ignore the part, in which
+    /// case the file is uploaded to the library as usual. Playlists can't be
+    /// created this way.
+    #[cfg(feature = "playlists")]
+    pub async fn upload_to(
+        &self,
+        playlist: &str,
+        filename: impl AsRef<Path>,
+        len: u64,
+        mime: Mime,
+        data: impl Into<reqwest::Body>,
+    ) -> super::Result<UploadOutcome> {
+        self.send_upload(
+            filename.as_ref(),
+            Some(len),
+            mime.as_ref(),
+            data.into(),
+            ExtraParts {
+                playlist: Some(playlist),
+                ..Default::default()
+            },
+            None,
+        )
+        .await
+    }
+
+    /// Uploads a file to the device with the given content type, rather than
+    /// a parsed `Mime`. This is for when the caller knows the type better than
+    /// a guess from the file name, such as one the device lists but
+    /// `mime_guess` doesn't know.
+    ///
+    /// Fails with [`ApiError::InvalidMime`] if `content_type` can't be used
+    /// as a header value.
+    pub async fn upload_with_content_type(
+        &self,
+        filename: impl AsRef<Path>,
+        len: u64,
+        content_type: &str,
+        data: impl Into<reqwest::Body>,
+    ) -> super::Result<UploadOutcome> {
+        self.send_upload(
+            filename.as_ref(),
+            Some(len),
+            content_type,
+            data.into(),
+            ExtraParts::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Uploads data read from `reader` to the device, for data that isn't in
+    /// a file, such as audio held in memory or generated on the fly.
+    ///
+    /// If `len` isn't known, the request is sent with chunked transfer
+    /// encoding instead of a `Content-Length`. The device hasn't been seen
+    /// receiving chunked uploads, so pass the length whenever it's known.
+    pub async fn upload_from_reader(
+        &self,
+        filename: impl AsRef<Path>,
+        len: Option<u64>,
+        mime: Mime,
+        reader: impl AsyncRead + Send + Sync + 'static,
+    ) -> super::Result<UploadOutcome> {
+        self.send_upload(
+            filename.as_ref(),
+            len,
+            mime.as_ref(),
+            reader_body(reader),
+            ExtraParts::default(),
+            None,
+        )
+        .await
+    }
+
+    async fn send_upload(
+        &self,
+        filename: &Path,
+        len: Option<u64>,
+        content_type: &str,
+        data: reqwest::Body,
+        extra: ExtraParts<'_>,
+        on_progress: Option<ProgressFn>,
+    ) -> super::Result<UploadOutcome> {
+        let basename = self.upload_basename(filename)?;
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        let progress = on_progress.map(|on_progress| {
+            let progress = ThrottledProgress::new(on_progress, self.progress_throttle);
+            Arc::new(Mutex::new(progress))
+        });
+        let data = hashing_body(data, hasher.clone(), progress.clone());
+        let flush_progress = || {
+            if let Some(progress) = &progress {
+                progress.lock().unwrap().flush();
+            }
+        };
+
+        let part = match len {
+            Some(len) => multipart::Part::stream_with_length(data, len),
+            None => multipart::Part::stream(data),
+        };
+        let mut form = multipart::Form::new()
             .part(
-                "file",
-                multipart::Part::stream_with_length(data, len)
-                    .file_name(basename)
-                    .mime_str(mime.as_ref())
-                    .unwrap(),
+                self.filename_field.clone(),
+                multipart::Part::text(basename.clone()),
+            )
+            .part(
+                self.file_field.clone(),
+                part.file_name(basename)
+                    .mime_str(content_type)
+                    .map_err(|_| ApiError::InvalidMime(content_type.to_string()))?,
+            );
+        if let Some(playlist) = extra.playlist.or(self.playlist.as_deref()) {
+            form = form.text(PLAYLIST_FIELD, playlist.to_string());
+        }
+        if let Some((art_mime, art_data)) = extra.artwork {
+            form = form.part(
+                "artwork",
+                multipart::Part::stream(art_data)
+                    .file_name(format!("artwork.{}", art_mime.subtype()))
+                    .mime_str(art_mime.as_ref())
+                    .map_err(|_| ApiError::InvalidMime(art_mime.to_string()))?,
             );
+        }
         let response = self
             .http_client
             .post(self.base_uri.join("upload").unwrap())
             .multipart(form)
             .send()
             .await?;
+        // The whole body has been sent once there's a response
+        flush_progress();
 
-        let _ = response.bytes().await?;
-        Ok(())
+        let body = check_status(response)?.bytes().await?;
+        let sha256 = hex_digest(&hasher);
+        let device_sha256 = serde_json::from_slice::<model::UploadResponse>(&body)
+            .ok()
+            .and_then(|r| r.checksum);
+
+        if self.verify_checksum {
+            if let Some(received) = &device_sha256 {
+                if !received.eq_ignore_ascii_case(&sha256) {
+                    return Err(ApiError::ChecksumMismatch {
+                        sent: sha256,
+                        received: received.clone(),
+                    });
+                }
+            }
+        }
+
+        Ok(UploadOutcome {
+            sha256,
+            device_sha256,
+        })
+    }
+
+    /// Uploads several files to the device, sending up to
+    /// [`MAX_BATCH_FILES`] of them in each request. Each file is given as its
+    /// name, length, type and data, and an outcome is returned for each, in
+    /// the same order.
+    ///
+    /// This can be much faster than uploading many small files one at a time.
+    /// However, the upload endpoint has only been seen taking one file per
+    /// request, so the device may keep just the first file of each batch, or
+    /// reject the request. Checksums aren't reported for batches.
+    #[cfg(feature = "batch")]
+    pub async fn upload_batch<P: AsRef<Path>, B: Into<reqwest::Body>>(
+        &self,
+        files: impl IntoIterator<Item = (P, u64, Mime, B)>,
+    ) -> super::Result<Vec<UploadOutcome>> {
+        let mut files = files.into_iter().peekable();
+        let mut outcomes = Vec::new();
+        while files.peek().is_some() {
+            let batch = files.by_ref().take(MAX_BATCH_FILES);
+            outcomes.extend(self.send_batch(batch).await?);
+        }
+        Ok(outcomes)
+    }
+
+    #[cfg(feature = "batch")]
+    async fn send_batch<P: AsRef<Path>, B: Into<reqwest::Body>>(
+        &self,
+        files: impl Iterator<Item = (P, u64, Mime, B)>,
+    ) -> super::Result<Vec<UploadOutcome>> {
+        let mut form = multipart::Form::new();
+        let mut hashers = Vec::new();
+        for (filename, len, mime, data) in files {
+            let basename = self.upload_basename(filename.as_ref())?;
+            let hasher = Arc::new(Mutex::new(Sha256::new()));
+            let data = hashing_body(data.into(), hasher.clone(), None);
+            hashers.push(hasher);
+            form = form
+                .part(
+                    self.filename_field.clone(),
+                    multipart::Part::text(basename.clone()),
+                )
+                .part(
+                    self.file_field.clone(),
+                    multipart::Part::stream_with_length(data, len)
+                        .file_name(basename)
+                        .mime_str(mime.as_ref())
+                        .map_err(|_| ApiError::InvalidMime(mime.to_string()))?,
+                );
+        }
+
+        if let Some(playlist) = &self.playlist {
+            form = form.text(PLAYLIST_FIELD, playlist.clone());
+        }
+        let response = self
+            .http_client
+            .post(self.base_uri.join("upload").unwrap())
+            .multipart(form)
+            .send()
+            .await?;
+        check_status(response)?.bytes().await?;
+
+        Ok(hashers
+            .iter()
+            .map(|hasher| UploadOutcome {
+                sha256: hex_digest(hasher),
+                device_sha256: None,
+            })
+            .collect())
+    }
+
+    /// Gets the name a file is uploaded with: the last component of
+    /// `filename`, normalized as configured.
+    fn upload_basename(&self, filename: &Path) -> super::Result<String> {
+        let basename = filename
+            .file_name()
+            .ok_or(ApiError::InvalidPath)?
+            .to_string_lossy()
+            .to_string();
+        Ok(self.name_normalization.apply(basename))
+    }
+}
+
+/// Streams upload data from `reader` in chunks of [`UPLOAD_CHUNK_SIZE`], so
+/// only one chunk is held at a time however large the data is.
+fn reader_body(reader: impl AsyncRead + Send + Sync + 'static) -> reqwest::Body {
+    reqwest::Body::wrap_stream(ReaderStream::with_capacity(reader, UPLOAD_CHUNK_SIZE))
+}
+
+/// Wraps upload data so each chunk is hashed, and counted toward
+/// `progress`, as it's pulled into the request.
+fn hashing_body(
+    data: reqwest::Body,
+    hasher: Arc<Mutex<Sha256>>,
+    progress: Option<Arc<Mutex<ThrottledProgress>>>,
+) -> reqwest::Body {
+    reqwest::Body::wrap_stream(BodyStream::new(data).try_filter_map(move |frame| {
+        let chunk = frame.into_data().ok();
+        if let Some(chunk) = &chunk {
+            hasher.lock().unwrap().update(chunk);
+            if let Some(progress) = &progress {
+                progress.lock().unwrap().sent(chunk.len() as u64);
+            }
+        }
+        future::ok(chunk)
+    }))
+}
+
+/// Gets the SHA-256 hashed so far as lowercase hex.
+fn hex_digest(hasher: &Mutex<Sha256>) -> String {
+    format!("{:x}", hasher.lock().unwrap().clone().finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn x_prefixed_type_only_matches_when_lenient() {
+        let list = ["audio/x-flac"];
+        let flac: Mime = "audio/flac".parse().unwrap();
+        assert!(mime_in_list(&list, &flac, false));
+        assert!(!mime_in_list(&list, &flac, true));
+    }
+
+    #[test]
+    fn exact_type_matches_when_strict() {
+        let list = ["audio/flac"];
+        let flac: Mime = "audio/flac; charset=binary".parse().unwrap();
+        assert!(mime_in_list(&list, &flac, true));
+        // The fallback only adds the prefix, it never removes it
+        let x_flac: Mime = "audio/x-flac".parse().unwrap();
+        assert!(!mime_in_list(&list, &x_flac, false));
+    }
+
+    #[test]
+    fn brackets_outside_the_host_are_not_zones() {
+        let url = LanUrl::parse("http://192.168.1.20:8080/files?name=[a%25b]").unwrap();
+        assert_eq!(url.scoped_addr(), None);
+        assert_eq!(url.address(), "192.168.1.20:8080");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn escaped_zone_names_an_interface() {
+        // Loopback is named "lo" on Linux and "lo0" on macOS and the BSDs
+        let (name, index) = ["lo", "lo0"]
+            .into_iter()
+            .find_map(|name| Some((name, interface_index(name)?)))
+            .unwrap();
+        let url = LanUrl::parse(&format!("http://[fe80::1%25{name}]:8080/")).unwrap();
+        assert_eq!(url.scoped_addr().unwrap().scope_id(), index);
+
+        let err = LanUrl::parse("http://[fe80::1%25nosuchif]:8080/").unwrap_err();
+        assert!(matches!(err, ApiError::UnknownInterface(name) if name == "nosuchif"));
+    }
+
+    #[tokio::test]
+    async fn large_files_are_read_a_bounded_chunk_at_a_time() {
+        // Sparse, so this doesn't take up the space on disk
+        let file = tempfile::tempfile().unwrap();
+        let len = 64 * 1024 * 1024;
+        file.set_len(len).unwrap();
+
+        let sent = Arc::new(Mutex::new((0, 0)));
+        let on_progress: ProgressFn = Arc::new({
+            let sent = sent.clone();
+            move |bytes| {
+                let (total, largest) = &mut *sent.lock().unwrap();
+                *total += bytes;
+                *largest = bytes.max(*largest);
+            }
+        });
+        let progress = ThrottledProgress::new(on_progress, ProgressThrottle::default());
+        let hasher = Arc::new(Mutex::new(Sha256::new()));
+        let body = hashing_body(
+            reader_body(tokio::fs::File::from_std(file)),
+            hasher,
+            Some(Arc::new(Mutex::new(progress))),
+        );
+
+        // Each chunk is dropped before the next is read, as when it's sent
+        BodyStream::new(body)
+            .try_for_each(|_| future::ok(()))
+            .await
+            .unwrap();
+        let (total, largest) = *sent.lock().unwrap();
+        assert_eq!(total, len);
+        assert!(largest <= UPLOAD_CHUNK_SIZE as u64);
     }
 }
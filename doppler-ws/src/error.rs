@@ -24,4 +24,117 @@ pub enum ApiError {
     InvalidUrl(#[from] url::ParseError),
     #[error("The provided path was invalid")]
     InvalidPath,
+    #[error("Device could not be reached: {0}")]
+    DeviceUnreachable(reqwest::Error),
+    #[error("Checksum mismatch: sent {sent}, device received {received}")]
+    ChecksumMismatch { sent: String, received: String },
+    #[error("Connection closed by server ({code}): {reason}")]
+    ConnectionClosed { code: u16, reason: String },
+    #[error("Upload was canceled")]
+    Cancelled,
+    #[error("Timed out after {0:?}")]
+    Timeout(std::time::Duration),
+    #[error("Invalid content type: {0}")]
+    InvalidMime(String),
+    #[error("Unknown network interface '{0}' in LAN URL")]
+    UnknownInterface(String),
+    /// The device turned the request away because another Wi-Fi Transfer
+    /// session is using it, assumed from a `409 Conflict` or `423 Locked`
+    /// response, as how the device reports a busy session hasn't been seen.
+    #[error("Device is busy with another transfer")]
+    DeviceBusy,
+    /// The device no longer accepts requests from this session, assumed from
+    /// a `401 Unauthorized` or `403 Forbidden` response, as how the device
+    /// reports an expired session hasn't been seen. Pairing again gets a new
+    /// session, usually at a new LAN URL.
+    #[error("Device session expired, pair again to continue")]
+    SessionExpired,
+}
+
+impl ApiError {
+    /// Returns whether the error is likely transient, such as a timeout or a
+    /// dropped connection, meaning the operation may succeed if retried.
+    ///
+    /// Errors caused by bad input or unexpected responses are never
+    /// retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Io(err) => io_retryable(err),
+            Self::Websocket(err) => match err {
+                tokio_websockets::Error::AlreadyClosed
+                | tokio_websockets::Error::CannotResolveHost => true,
+                tokio_websockets::Error::Io(err) => io_retryable(err),
+                _ => false,
+            },
+            Self::Http(err) => err.is_timeout() || err.is_connect(),
+            // The device may be back once it's on the network again
+            Self::DeviceUnreachable(_) => true,
+            // Most likely corrupted in transit, so sending again may work
+            Self::ChecksumMismatch { .. } => true,
+            Self::Timeout(_) => true,
+            Self::Serde(_)
+            | Self::MalformedResponse
+            | Self::BadResponse(_)
+            | Self::UnexpectedDevice
+            | Self::DeviceIdMissing
+            | Self::InvalidUrl(_)
+            | Self::InvalidPath
+            | Self::InvalidMime(_)
+            | Self::UnknownInterface(_)
+            // Only clears once the user closes the other session
+            | Self::DeviceBusy
+            // Needs a new session from pairing again
+            | Self::SessionExpired
+            | Self::ConnectionClosed { .. }
+            | Self::Cancelled => false,
+        }
+    }
+
+    /// Returns a stable identifier for the kind of error, such as
+    /// `"device_unreachable"`, for logging and branching on.
+    ///
+    /// Unlike the messages, these won't change between releases.
+    ///
+    /// ```
+    /// # use doppler_ws::error::ApiError;
+    /// assert_eq!(ApiError::Cancelled.code(), "cancelled");
+    /// assert_eq!(ApiError::InvalidPath.code(), "invalid_path");
+    /// ```
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::Io(_) => "io",
+            Self::Serde(_) => "serde",
+            Self::Websocket(_) => "ws",
+            Self::MalformedResponse => "malformed_response",
+            Self::BadResponse(_) => "bad_response",
+            Self::Http(_) => "http",
+            Self::UnexpectedDevice => "unexpected_device",
+            Self::DeviceIdMissing => "device_id_missing",
+            Self::InvalidUrl(_) => "invalid_url",
+            Self::InvalidPath => "invalid_path",
+            Self::DeviceUnreachable(_) => "device_unreachable",
+            Self::ChecksumMismatch { .. } => "checksum_mismatch",
+            Self::ConnectionClosed { .. } => "connection_closed",
+            Self::Cancelled => "cancelled",
+            Self::Timeout(_) => "timeout",
+            Self::InvalidMime(_) => "invalid_mime",
+            Self::UnknownInterface(_) => "unknown_interface",
+            Self::DeviceBusy => "device_busy",
+            Self::SessionExpired => "session_expired",
+        }
+    }
+}
+
+/// Checks whether an I/O error represents a timeout or dropped connection.
+fn io_retryable(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
 }
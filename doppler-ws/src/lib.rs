@@ -21,14 +21,17 @@
 //! To pair with a device using the pairing code:
 //!
 //! ```no_run
-//! let client = TransferClient::connect().await?;
+//! # use doppler_ws::TransferClient;
+//! # fn do_we_have_device_id(_id: &str) -> bool { false }
+//! # async fn pair() -> doppler_ws::Result<()> {
+//! let mut client = TransferClient::connect().await?;
 //!
 //! // This can be given to the user as-is and/or as a QR Code
 //! let pairing_code = client.code();
 //! println!("Use {pairing_code} in your app to connect.");
 //!
 //! // Wait for the user to enter the code
-//! let mut response = client.get_new_device().await?;
+//! let response = client.get_new_device().await?;
 //!
 //! // Check whether the device is saved. Storage of devices should be handled
 //! // by your application. This is not strictly required, but is how the
@@ -36,16 +39,30 @@
 //! let is_saved = do_we_have_device_id(response.id());
 //!
 //! // Paired! Now we're connected directly to the device.
-//! let device = client.confirm_device(&mut response, is_saved).await?;
+//! let paired = client.confirm(&response, is_saved).await?;
+//! if paired.save_requested {
+//!     // Save `paired.client.push_token()` to pair without the code next time
+//! }
+//! let device = paired.client;
+//! # Ok(())
+//! # }
 //! ```
 //!
+//! The code is always assigned by the API when the websocket is opened. There
+//! is no known way to join with a code chosen by the client or generated on
+//! the phone, so pairing can only go in this direction. If the current code
+//! expires, [`TransferClient::refresh_code`] requests a new one.
+//!
 //! ## Pairing with a saved device
 //!
 //! If you have a [`Device`] saved (for example, serialized in a database), you
 //! can initiate the pairing process without needing to show the code to the user:
 //!
 //! ```no_run
-//! let client = TransferClient::connect().await?;
+//! # use doppler_ws::{model::Device, TransferClient};
+//! # fn get_device_from_database_somehow() -> Device { unimplemented!() }
+//! # async fn pair() -> doppler_ws::Result<()> {
+//! let mut client = TransferClient::connect().await?;
 //! // Pull the Device object from your database. `Device` derives
 //! // Serialize/Deserialize, so you can use your favorite serde crate to store
 //! // it.
@@ -53,34 +70,97 @@
 //!
 //! // This sends a push notification to the user's device asking to open the
 //! // app so we can connect. This function will return once that's done.
-//! let mut response = client.get_saved_device(our_device).await?;
+//! let response = client.get_saved_device(&our_device).await?;
 //!
 //! // And that's it!
-//! let device = client.confirm_device(&mut response, is_saved).await?;
+//! let device = client.confirm(&response, true).await?.client;
+//! # Ok(())
+//! # }
 //! ```
 //!
+//! # Blocking API
+//!
+//! With the `blocking` feature enabled, the `blocking` module provides
+//! versions of both clients that don't require an async runtime.
+//!
+//! # Testing
+//!
+//! Code that only uploads can be written against the [`device::Upload`] trait.
+//! With the `mock` feature enabled, `device::MockDevice` implements it without
+//! a device on the network, reading and hashing each file instead. Pairing
+//! flows can be run against scripted responses from the API by giving
+//! `connection::ScriptedTransport` to
+//! [`TransferClientBuilder::connect_over`].
+//!
 //! [doppler-transfer.com]: https://doppler-transfer.com
 
+use std::{collections::VecDeque, future::Future, time::Duration};
+
+use connection::{ConnectOptions, Transport, WsStream};
 use error::ApiError;
-use futures_util::{SinkExt, TryStreamExt};
+use futures_util::{stream, Stream, StreamExt};
 use model::Device;
-use tokio::net::TcpStream;
-use tokio_websockets::{MaybeTlsStream, Message, WebSocketStream};
+use tracing::Instrument;
 
+pub mod backoff;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+pub mod connection;
 pub mod device;
 pub mod error;
 pub mod model;
 
+pub use connection::TransferClientBuilder;
+pub use http::HeaderValue;
+pub use tokio_util::sync::CancellationToken;
+pub use uuid::Uuid;
+
 pub type Result<T> = std::result::Result<T, ApiError>;
 
+/// The version of this crate.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The User-Agent sent when none is set with
+/// [`TransferClientBuilder::user_agent`].
+pub const USER_AGENT: &str = concat!("doppler-ws/", env!("CARGO_PKG_VERSION"));
+
 const API_DOMAIN: &str = "doppler-transfer.com";
 
+/// How long to wait for the API to acknowledge closing the connection.
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upper limit on a response split across several messages, so one that's
+/// never completed can't grow without bound.
+const MAX_PARTIAL_LEN: usize = 1024 * 1024;
+
 /// A connection to the Wi-Fi Transfer API. This is used solely for pairing.
-pub struct TransferClient {
+///
+/// The API is reached over a websocket, unless another [`Transport`] is given
+/// with [`TransferClientBuilder::connect_over`].
+///
+/// # Cancellation
+///
+/// [`TransferClient::get_new_device`] and
+/// [`TransferClient::wait_for_saved_device`] are cancel safe, so they can be
+/// used in a branch of `tokio::select!` or under a timeout. If the future is
+/// dropped before it completes, no message from the API is lost: one that
+/// arrived in the meantime is kept for the next call, as is the start of one
+/// split across several websocket messages.
+///
+/// [`TransferClient::confirm`] isn't cancel safe, as it sends the
+/// confirmation before waiting for the reply. If it's dropped, pair again
+/// with a new code.
+pub struct TransferClient<T = WsStream> {
     http_client: reqwest::Client,
-    ws_client: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    transport: T,
+    config: TransferClientBuilder,
     code: String,
-    msg_queue: Vec<model::ApiResponse>,
+    /// Messages received while waiting for another kind, oldest first.
+    msg_queue: VecDeque<model::ApiResponse>,
+    /// The start of a response whose remainder hasn't arrived yet.
+    partial: String,
+    /// Groups the events logged over the client's lifetime.
+    span: tracing::Span,
 }
 
 // Pulls the actual API response we want out of the ApiResponse enum
@@ -99,88 +179,374 @@ macro_rules! get_response {
 
 impl TransferClient {
     /// Connects to the Doppler Transfer API.
+    ///
+    /// Use a [`TransferClientBuilder`] to configure the connection, such as
+    /// to set timeouts.
     pub async fn connect() -> Result<Self> {
-        use tokio_websockets::ClientBuilder;
+        TransferClientBuilder::default().connect().await
+    }
+
+    /// Connects to the Doppler Transfer API, identifying as the given client
+    /// ID rather than a random one. Persisting the ID lets a machine present
+    /// itself consistently across sessions.
+    pub async fn connect_with_id(id: Uuid) -> Result<Self> {
+        TransferClientBuilder::default()
+            .client_id(id)
+            .connect()
+            .await
+    }
 
-        let random_id = uuid::Uuid::new_v4();
+    /// Connects to the Doppler Transfer API, using the given options such as
+    /// a proxy or custom TLS configuration.
+    ///
+    /// If [`ConnectOptions::connect_retries`] is set, connecting is retried
+    /// on errors that are likely transient, such as a timeout or dropped
+    /// connection. Errors from a server that responded unexpectedly aren't
+    /// retried.
+    pub async fn connect_with(options: ConnectOptions) -> Result<Self> {
+        TransferClientBuilder::from(options).connect().await
+    }
+
+    /// Connects to the Doppler Transfer API with the builder's settings.
+    pub(crate) async fn from_builder(config: TransferClientBuilder) -> Result<Self> {
+        let span = new_span();
+        let mut attempt = 0;
+        let ws_client = loop {
+            match Self::open_websocket(&config).instrument(span.clone()).await {
+                Ok(ws_client) => break ws_client,
+                Err(err) => {
+                    Self::wait_to_retry(&config, err, &mut attempt)
+                        .instrument(span.clone())
+                        .await?
+                }
+            }
+        };
+
+        let mut new_self = Self::from_parts(ws_client, config, span)?;
+        let mut result = new_self.read_code().await;
+        while let Err(err) = result {
+            let span = new_self.span.clone();
+            Self::wait_to_retry(&new_self.config, err, &mut attempt)
+                .instrument(span)
+                .await?;
+            result = new_self.refresh_code().await.map(|_| ());
+        }
+
+        Ok(new_self)
+    }
+
+    /// Waits before another connection attempt, or returns the error if it
+    /// shouldn't be retried.
+    async fn wait_to_retry(
+        config: &TransferClientBuilder,
+        err: ApiError,
+        attempt: &mut u32,
+    ) -> Result<()> {
+        let retries = config.options.connect_retries;
+        if *attempt >= retries || !err.is_retryable() {
+            return Err(err);
+        }
+        *attempt += 1;
+        // Jitter only ever shortens the delay
+        let delay = config.connect_backoff.delay(*attempt);
+        tracing::warn!(
+            "Connecting to pairing API failed, retrying in up to {delay:?} ({attempt}/{retries}): \
+             {err}"
+        );
+        config.connect_backoff.wait(*attempt).await;
+        Ok(())
+    }
+
+    /// Opens a new websocket to the pairing API.
+    async fn open_websocket(config: &TransferClientBuilder) -> Result<WsStream> {
+        let options = &config.options;
+        let client_id = options.client_id.unwrap_or_else(Uuid::new_v4);
         let doppler_url = http::Uri::builder()
             .scheme("wss")
-            .authority(API_DOMAIN)
-            .path_and_query(format!("/api/v1/code?id={random_id}"))
+            .authority(config.domain.as_str())
+            .path_and_query(format!("/api/v1/code?id={client_id}"))
             .build()
-            .unwrap();
-        let (ws_client, _) = ClientBuilder::from_uri(doppler_url).connect().await?;
+            .map_err(|_| ApiError::InvalidUrl(url::ParseError::InvalidDomainCharacter))?;
+        tracing::debug!(%client_id, proxy = ?options.proxy, "Connecting to pairing API");
+        let ws_client =
+            with_timeout(config.http_timeout, options.open_websocket(doppler_url)).await?;
+        tracing::debug!("Connected to pairing API");
+        Ok(ws_client)
+    }
 
-        let mut new_self = Self {
-            http_client: reqwest::Client::new(),
-            ws_client,
-            code: String::new(), // placeholder
-            msg_queue: Vec::new(),
-        };
+    /// Requests a new pairing code, such as when the previous one expired,
+    /// and returns it.
+    ///
+    /// The API only hands out a code when the websocket is opened, so this
+    /// transparently reconnects. Any messages received on the old connection
+    /// that haven't been consumed yet are discarded, as they refer to the old
+    /// code.
+    pub async fn refresh_code(&mut self) -> Result<&str> {
+        let span = self.span.clone();
+        async {
+            tracing::debug!("Reconnecting for a new pairing code");
+            let ws_client = Self::open_websocket(&self.config).await?;
+            let mut old_client = std::mem::replace(&mut self.transport, ws_client);
+            // The old session is done with either way, so don't fail on this
+            if let Err(err) = with_timeout(Some(CLOSE_TIMEOUT), old_client.close()).await {
+                tracing::debug!("Error closing old connection: {err}");
+            }
+            self.msg_queue.clear();
+            self.partial.clear();
+
+            self.read_code().await
+        }
+        .instrument(span)
+        .await?;
 
-        let code_data = get_response!(new_self, Code);
-        new_self.code = code_data.code;
+        Ok(&self.code)
+    }
+}
 
+impl<T: Transport> TransferClient<T> {
+    /// Starts a client over the given transport with the builder's settings.
+    pub(crate) async fn from_transport(
+        transport: T,
+        config: TransferClientBuilder,
+    ) -> Result<Self> {
+        let mut new_self = Self::from_parts(transport, config, new_span())?;
+        new_self.read_code().await?;
         Ok(new_self)
     }
 
+    /// Creates the client, before the pairing code has been received.
+    fn from_parts(
+        transport: T,
+        mut config: TransferClientBuilder,
+        span: tracing::Span,
+    ) -> Result<Self> {
+        Ok(Self {
+            http_client: config.take_http_client()?,
+            transport,
+            config,
+            code: String::new(), // placeholder
+            msg_queue: VecDeque::new(),
+            partial: String::new(),
+            span,
+        })
+    }
+
+    /// Waits for the API to assign a pairing code.
+    async fn read_code(&mut self) -> Result<()> {
+        let code_data = get_response!(self, Code);
+        self.code = code_data.code;
+        self.span.record("code", &self.code);
+        Ok(())
+    }
+
     /// Returns a reference to the device pairing code.
+    ///
+    /// The code is assigned by the API; it can't be chosen by the client.
     pub fn code(&self) -> &str {
         &self.code
     }
 
+    /// Renders the pairing code as a QR code in SVG, for web UIs. The app
+    /// scans the bare code, as shown on the Wi-Fi Transfer website.
+    #[cfg(feature = "qr")]
+    pub fn qr_svg(&self) -> String {
+        qrencode::QrCode::new(&self.code)
+            .expect("pairing codes are short enough for a QR code")
+            .render::<qrencode::render::svg::Color>()
+            .min_dimensions(200, 200)
+            .build()
+    }
+
+    /// Returns the number of messages received but not yet consumed.
+    ///
+    /// Messages are queued when they arrive while waiting for another kind,
+    /// so a growing count while pairing hangs points to the API sending
+    /// messages this crate doesn't expect.
+    pub fn pending_messages(&self) -> usize {
+        self.msg_queue.len()
+    }
+
+    /// Returns a stream of every message from the API as it arrives.
+    ///
+    /// This is an escape hatch for debugging or building alternate pairing
+    /// flows. Messages already received but not yet consumed are yielded
+    /// first. Messages taken from this stream won't be seen by the other
+    /// methods, such as [`TransferClient::get_new_device`].
+    pub fn events(&mut self) -> impl Stream<Item = Result<model::ApiResponse>> + '_ {
+        let queued = std::mem::take(&mut self.msg_queue);
+        let span = self.span.clone();
+        let state = (&mut self.transport, &mut self.partial);
+        let incoming = stream::try_unfold(state, move |(transport, partial)| {
+            let span = span.clone();
+            async move {
+                let response = recv_response(transport, partial).instrument(span).await?;
+                Ok(response.map(|response| (response, (transport, partial))))
+            }
+        });
+        stream::iter(queued.into_iter().map(Ok)).chain(incoming)
+    }
+
+    /// Drops the queued messages that match `filter`, returning how many
+    /// there were.
+    fn discard_queued(&mut self, filter: impl Fn(&model::ApiResponse) -> bool) -> usize {
+        let before = self.msg_queue.len();
+        self.msg_queue.retain(|response| !filter(response));
+        before - self.msg_queue.len()
+    }
+
     /// Get the next text message.
+    ///
+    /// This is cancel safe as long as the transport's `recv` is. Every await
+    /// is a read from the transport, and a message that's been read is
+    /// queued or returned without awaiting anything else, so dropping the
+    /// future can't lose one. The start of a split message is kept in
+    /// `partial` rather than in the future.
     async fn next_msg(
         &mut self,
         filter: impl Fn(&model::ApiResponse) -> bool,
     ) -> Result<model::ApiResponse> {
         // First, see if we already received a message of the given filter
-        if let Some(idx) = self.msg_queue.iter().position(&filter) {
-            Ok(self.msg_queue.remove(idx))
+        if let Some(response) = self
+            .msg_queue
+            .iter()
+            .position(&filter)
+            .and_then(|idx| self.msg_queue.remove(idx))
+        {
+            tracing::trace!(pending = self.msg_queue.len(), "Consumed queued message");
+            Ok(response)
         } else {
-            while let Some(msg) = self.ws_client.try_next().await? {
-                if let Some(text) = msg.as_text() {
-                    let response: model::ApiResponse = serde_json::from_str(text)?;
+            let span = self.span.clone();
+            let timeout = self.config.pairing_timeout;
+            let max_queued = self.config.max_queued.max(1);
+            let wait = async {
+                while let Some(response) =
+                    recv_response(&mut self.transport, &mut self.partial).await?
+                {
                     if filter(&response) {
                         return Ok(response);
                     } else {
                         // Not our message, add it to the queue and loop
-                        self.msg_queue.push(response);
+                        if self.msg_queue.len() >= max_queued {
+                            let dropped = self.msg_queue.pop_front();
+                            tracing::warn!(
+                                ?dropped,
+                                "Too many unhandled messages from the pairing API, \
+                                 dropping the oldest"
+                            );
+                        }
+                        self.msg_queue.push_back(response);
+                        tracing::trace!(
+                            pending = self.msg_queue.len(),
+                            "Queued unexpected message"
+                        );
                     }
                 }
-            }
-            // Stream ended?
-            Err(ApiError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+                // Stream ended?
+                tracing::debug!("Pairing API connection ended");
+                Err(ApiError::Io(std::io::ErrorKind::UnexpectedEof.into()))
+            };
+            with_timeout(timeout, wait).instrument(span).await
         }
     }
 
-    /// Completes the pairing process. If successful, a `DeviceClient` is
-    /// returned.
+    /// Completes the pairing process. If successful, the connection to the
+    /// device is returned, along with whether the device asked to be saved.
     ///
     /// If the device was already saved, set `is_saved` to true.
+    ///
+    /// This also records `is_saved` on the response. Use
+    /// [`TransferClient::confirm`] to leave the response untouched.
     pub async fn confirm_device(
         &mut self,
         device: &mut model::DeviceResponse,
         is_saved: bool,
-    ) -> Result<device::DeviceClient> {
+    ) -> Result<device::PairedDevice> {
         device.is_saved = Some(is_saved);
-        let str_response = serde_json::to_string(&device)?;
-        self.ws_client.send(Message::text(str_response)).await?;
+        self.confirm(device, is_saved).await
+    }
+
+    /// Completes the pairing process. If successful, the connection to the
+    /// device is returned, along with whether the device asked to be saved.
+    ///
+    /// If the device was already saved, set `is_saved` to true.
+    ///
+    /// The API doesn't tag its replies, so the device's LAN URL is taken from
+    /// the first one to arrive after the confirmation is sent. Any that
+    /// arrived before are stale and discarded. If the reply carries a push
+    /// token for a different device, [`ApiError::UnexpectedDevice`] is
+    /// returned rather than pairing with the wrong one.
+    pub async fn confirm(
+        &mut self,
+        device: &model::DeviceResponse,
+        is_saved: bool,
+    ) -> Result<device::PairedDevice> {
+        device.check_type()?;
+        let confirmation = model::DeviceConfirmation {
+            device_type: &device.device_type,
+            id: &device.id,
+            is_saved,
+        };
+        let str_response = serde_json::to_string(&confirmation)?;
+        let stale = self.discard_queued(|r| matches!(r, model::ApiResponse::LanUrl(_)));
+        if stale > 0 {
+            tracing::warn!(stale, "Discarding LAN URLs received before confirming");
+        }
+        self.transport.send(str_response).await?;
         let lan_url = get_response!(self, LanUrl);
-        device::DeviceClient::new(&lan_url.url_lan, lan_url.push_token).await
+        let token_id = lan_url.push_token.as_ref().and_then(|t| t.id.as_deref());
+        if token_id.is_some_and(|id| id != device.id) {
+            tracing::debug!(?token_id, "LAN URL is for another device");
+            return Err(ApiError::UnexpectedDevice);
+        }
+        let save_requested = lan_url.push_token.is_some();
+        let mut builder = device::DeviceClientBuilder::new(lan_url.url_lan)
+            .http_version(self.config.http_version)
+            .accept_invalid_certs(self.config.accept_invalid_device_certs);
+        if let Some(push_token) = lan_url.push_token {
+            builder = builder.push_token(push_token);
+        }
+        if let Some(timeout) = self.config.http_timeout {
+            builder = builder.http_timeout(timeout);
+        }
+        if let Some(user_agent) = &self.config.options.user_agent {
+            builder = builder.user_agent(user_agent.clone());
+        }
+        let client = builder.connect().await?;
+        Ok(device::PairedDevice {
+            client,
+            save_requested,
+        })
     }
 
     /// Waits for a device to pair with the pairing code.
+    ///
+    /// This is cancel safe; see [`TransferClient#cancellation`].
     pub async fn get_new_device(&mut self) -> Result<model::DeviceResponse> {
-        Ok(get_response!(self, Device))
+        let device = get_response!(self, Device);
+        device.check_type()?;
+        Ok(device)
     }
 
     /// Initiates the pairing process with a saved device by sending it a push
     /// notification.
+    ///
+    /// This waits for the device to respond for as long as the
+    /// [pairing timeout](TransferClientBuilder::pairing_timeout) allows. To
+    /// give up sooner and send the notification again, use
+    /// [`TransferClient::resend_push`] and
+    /// [`TransferClient::wait_for_saved_device`] instead.
     pub async fn get_saved_device(&mut self, device: &Device) -> Result<model::DeviceResponse> {
-        let Some(device_id) = &device.id else {
+        self.resend_push(device).await?;
+        self.wait_for_saved_device(device, None).await
+    }
+
+    /// Sends a saved device the push notification asking it to pair, such as
+    /// when it didn't respond to the last one. The connection to the API is
+    /// kept, so the pairing code stays the same.
+    pub async fn resend_push(&self, device: &Device) -> Result<()> {
+        if device.id.is_none() {
             return Err(ApiError::DeviceIdMissing);
-        };
+        }
 
         let req = model::SpecificDeviceRequest {
             code: self.code.clone(),
@@ -189,23 +555,252 @@ impl TransferClient {
 
         let response = self
             .http_client
-            .post(format!("https://{API_DOMAIN}/api/v0/request-device"))
+            .post(format!(
+                "https://{}/api/v0/request-device",
+                self.config.domain
+            ))
             .json(&req)
             .send()
             .await?;
         let status = response.status();
         // Workaround for current functionality
         if status.is_success() || status.as_u16() == 500 {
-            let next_device = get_response!(self, Device);
-            if next_device.id.eq(device_id) {
-                // This is ours!
-                Ok(next_device)
-            } else {
-                // TODO: Should we throw an error or just ignore it?
-                Err(ApiError::UnexpectedDevice)
-            }
+            Ok(())
         } else {
-            Err(ApiError::BadResponse(response.status()))
+            Err(ApiError::BadResponse(status))
         }
     }
+
+    /// Waits for a saved device to respond to a push notification, sent with
+    /// [`TransferClient::resend_push`].
+    ///
+    /// If `timeout` passes first, [`ApiError::Timeout`] is returned and the
+    /// client can keep being used, such as to send the notification again.
+    /// This is cancel safe; see [`TransferClient#cancellation`].
+    pub async fn wait_for_saved_device(
+        &mut self,
+        device: &Device,
+        timeout: Option<Duration>,
+    ) -> Result<model::DeviceResponse> {
+        let Some(device_id) = &device.id else {
+            return Err(ApiError::DeviceIdMissing);
+        };
+
+        let next_device = with_timeout(timeout, async { Ok(get_response!(self, Device)) }).await?;
+        next_device.check_type()?;
+        if next_device.id.eq(device_id) {
+            // This is ours!
+            Ok(next_device)
+        } else {
+            // TODO: Should we throw an error or just ignore it?
+            Err(ApiError::UnexpectedDevice)
+        }
+    }
+
+    /// Closes the connection to the API with a close handshake, so the
+    /// session doesn't linger on the server.
+    ///
+    /// Dropping the client instead tears the connection down without telling
+    /// the API. Waiting for the API to acknowledge gives up after a few
+    /// seconds with [`ApiError::Timeout`].
+    pub async fn close(mut self) -> Result<()> {
+        let span = self.span.clone();
+        async {
+            tracing::debug!("Closing connection");
+            with_timeout(Some(CLOSE_TIMEOUT), self.transport.close()).await
+        }
+        .instrument(span)
+        .await
+    }
+}
+
+/// Receives the next response from the API, or `None` if the connection
+/// ended.
+///
+/// A JSON document split across several messages is put back together
+/// before it's parsed, using `partial` to hold the start of it in the
+/// meantime. That's kept outside the future so a read that's cancelled
+/// partway through a document doesn't lose it.
+async fn recv_response<T: Transport>(
+    transport: &mut T,
+    partial: &mut String,
+) -> Result<Option<model::ApiResponse>> {
+    while let Some(text) = transport.recv().await? {
+        partial.push_str(&text);
+        match serde_json::from_str(partial) {
+            Ok(response) => {
+                partial.clear();
+                return Ok(Some(response));
+            }
+            Err(err) if err.is_eof() && partial.len() < MAX_PARTIAL_LEN => {
+                tracing::trace!(len = partial.len(), "Waiting for the rest of a message");
+            }
+            Err(err) => {
+                partial.clear();
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Creates the span grouping a client's events, filled in with the pairing
+/// code once it's known.
+fn new_span() -> tracing::Span {
+    tracing::debug_span!("transfer_client", code = tracing::field::Empty)
+}
+
+/// Runs `fut`, failing with [`ApiError::Timeout`] if it takes longer than
+/// `timeout`.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, fut)
+            .await
+            .map_err(|_| ApiError::Timeout(timeout))?,
+        None => fut.await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::*;
+    use crate::{backoff::Backoff, connection::ScriptedTransport};
+
+    const CODE: &str = r#"{"code":"123456"}"#;
+    const LAN_URL: &str = r#"{"url_lan":"http://192.168.1.2:8080/","push_token":null}"#;
+
+    fn timeout() -> ApiError {
+        ApiError::Timeout(Duration::from_secs(5))
+    }
+
+    fn device(id: &str) -> String {
+        format!(r#"{{"type":"device","device":"{id}","is_saved":null}}"#)
+    }
+
+    async fn scripted(
+        builder: TransferClientBuilder,
+        messages: impl IntoIterator<Item = String>,
+    ) -> TransferClient<ScriptedTransport> {
+        let messages = std::iter::once(CODE.to_string()).chain(messages);
+        builder
+            .connect_over(ScriptedTransport::new(messages))
+            .await
+            .unwrap()
+    }
+
+    fn is_lan_url(response: &model::ApiResponse) -> bool {
+        matches!(response, model::ApiResponse::LanUrl(_))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_retries_wait_with_backoff() {
+        let config = TransferClientBuilder::default()
+            .connect_retries(2)
+            .connect_backoff(Backoff {
+                base: Duration::from_secs(1),
+                ..Backoff::default()
+            });
+        let start = Instant::now();
+        let mut attempt = 0;
+
+        TransferClient::wait_to_retry(&config, timeout(), &mut attempt)
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        TransferClient::wait_to_retry(&config, timeout(), &mut attempt)
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+
+        // Out of retries, so the error is returned without waiting
+        let result = TransferClient::wait_to_retry(&config, timeout(), &mut attempt).await;
+        assert!(matches!(result, Err(ApiError::Timeout(_))));
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connect_errors_that_arent_transient_arent_retried() {
+        let config = TransferClientBuilder::default().connect_retries(2);
+        let start = Instant::now();
+        let mut attempt = 0;
+
+        let result =
+            TransferClient::wait_to_retry(&config, ApiError::MalformedResponse, &mut attempt).await;
+        assert!(matches!(result, Err(ApiError::MalformedResponse)));
+        assert_eq!(attempt, 0);
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test]
+    async fn messages_of_other_kinds_are_queued_until_wanted() {
+        let messages = [LAN_URL.to_string(), device("a")];
+        let mut client = scripted(TransferClientBuilder::default(), messages).await;
+
+        let device = client.get_new_device().await.unwrap();
+        assert_eq!(device.id(), "a");
+        assert_eq!(client.pending_messages(), 1);
+
+        let lan_url = client.next_msg(is_lan_url).await.unwrap();
+        assert!(is_lan_url(&lan_url));
+        assert_eq!(client.pending_messages(), 0);
+    }
+
+    #[tokio::test]
+    async fn queued_messages_are_consumed_oldest_first() {
+        let messages = [device("a"), device("b"), LAN_URL.to_string()];
+        let mut client = scripted(TransferClientBuilder::default(), messages).await;
+
+        client.next_msg(is_lan_url).await.unwrap();
+        assert_eq!(client.pending_messages(), 2);
+        assert_eq!(client.get_new_device().await.unwrap().id(), "a");
+        assert_eq!(client.get_new_device().await.unwrap().id(), "b");
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_oldest_message() {
+        let builder = TransferClientBuilder::default().max_queued_messages(2);
+        let messages = [device("a"), device("b"), device("c"), LAN_URL.to_string()];
+        let mut client = scripted(builder, messages).await;
+
+        client.next_msg(is_lan_url).await.unwrap();
+        assert_eq!(client.pending_messages(), 2);
+        assert_eq!(client.get_new_device().await.unwrap().id(), "b");
+        assert_eq!(client.get_new_device().await.unwrap().id(), "c");
+    }
+
+    #[tokio::test]
+    async fn discarding_queued_messages_keeps_those_not_matched() {
+        let messages = [LAN_URL.to_string(), device("a"), LAN_URL.to_string()];
+        let mut client = scripted(TransferClientBuilder::default(), messages).await;
+
+        // Nothing else arrives, so every message ends up queued
+        let result = client.next_msg(|_| false).await;
+        assert!(matches!(result, Err(ApiError::Io(_))));
+        assert_eq!(client.pending_messages(), 3);
+
+        assert_eq!(client.discard_queued(is_lan_url), 2);
+        assert_eq!(client.pending_messages(), 1);
+        assert_eq!(client.get_new_device().await.unwrap().id(), "a");
+    }
+
+    #[tokio::test]
+    async fn saved_device_must_match_the_one_waited_for() {
+        let saved = model::Device {
+            name: None,
+            id: Some("a".to_string()),
+            user: String::new(),
+            device: String::new(),
+        };
+        let mut client = scripted(TransferClientBuilder::default(), [device("b")]).await;
+
+        let result = client.wait_for_saved_device(&saved, None).await;
+        assert!(matches!(result, Err(ApiError::UnexpectedDevice)));
+    }
 }
@@ -1,13 +1,23 @@
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Number of characters of a device ID kept by the `short_id` helpers.
+const SHORT_ID_LEN: usize = 8;
+
+/// Shortens a device ID for display, keeping the whole ID if it's short or
+/// can't be cut cleanly.
+fn shorten_id(id: &str) -> &str {
+    id.get(..SHORT_ID_LEN).unwrap_or(id)
+}
 
 /// Response when a pairing code is requested.
 #[derive(Serialize, Deserialize, Debug)]
-pub(crate) struct CodeResponse {
+pub struct CodeResponse {
     pub code: String,
 }
 
 /// Represents a device.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Device {
     pub name: Option<String>,
     pub id: Option<String>,
@@ -16,6 +26,36 @@ pub struct Device {
 }
 
 impl Device {
+    /// Name used when a device doesn't have one.
+    pub const UNNAMED: &'static str = "device";
+
+    /// Returns the name of the device for showing to the user, falling back
+    /// to [`Device::UNNAMED`] if it has none.
+    ///
+    /// ```
+    /// # use doppler_ws::model::Device;
+    /// let mut device = Device {
+    ///     name: None,
+    ///     id: Some("0123456789abcdef".to_string()),
+    ///     user: "user".to_string(),
+    ///     device: "device".to_string(),
+    /// };
+    /// assert_eq!(device.display_name(), "device");
+    /// assert_eq!(device.short_id(), Some("01234567"));
+    ///
+    /// device.name = Some("Phone".to_string());
+    /// assert_eq!(device.display_name(), "Phone");
+    /// ```
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(Self::UNNAMED)
+    }
+
+    /// Returns the start of the device's ID, which is enough to tell devices
+    /// apart in logs.
+    pub fn short_id(&self) -> Option<&str> {
+        self.id.as_deref().map(shorten_id)
+    }
+
     /// Creates a "token" version of the device for use as the push token.
     pub(crate) fn for_request(&self) -> Self {
         Self {
@@ -25,14 +65,33 @@ impl Device {
             device: self.device.clone(),
         }
     }
+
+    /// Returns a stable identifier for the device, derived from the `user`
+    /// and `device` tokens as a SHA-256 hex digest.
+    ///
+    /// Unlike `id`, this is always available, and doesn't change if the
+    /// device pairs again under a different name.
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.user.as_bytes());
+        // Keep ("ab", "c") and ("a", "bc") apart
+        hasher.update([0]);
+        hasher.update(self.device.as_bytes());
+        format!("{:x}", hasher.finalize())
+    }
 }
 
 // ------ API Responses ------
 
 /// Represents all of the responses we might get from the API server.
+///
+/// These are normally consumed by the [`TransferClient`](crate::TransferClient)
+/// pairing methods, but can be observed directly with
+/// [`TransferClient::events`](crate::TransferClient::events).
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
-pub(crate) enum ApiResponse {
+#[non_exhaustive]
+pub enum ApiResponse {
     /// Should only be received on connect. Represents the code used to pair our
     /// program with the user's device.
     Code(CodeResponse),
@@ -48,7 +107,20 @@ pub(crate) enum ApiResponse {
 ///
 /// To confirm this device should be used, use the `TransferClient::confirm_device` function.
 ///
-#[derive(Debug, Serialize, Deserialize)]
+/// The API sends the device's ID in a field named `device`, and the kind of
+/// message in `type`. Serializing keeps those names, so a response can be
+/// stored and read back as it was received:
+///
+/// ```
+/// # use doppler_ws::model::DeviceResponse;
+/// let json = r#"{"type":"device","device":"0123456789abcdef","is_saved":null}"#;
+/// let response: DeviceResponse = serde_json::from_str(json).unwrap();
+/// assert_eq!(response.id(), "0123456789abcdef");
+/// assert_eq!(response.device_type(), "device");
+/// assert_eq!(response.is_saved(), None);
+/// assert_eq!(serde_json::to_string(&response).unwrap(), json);
+/// ```
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct DeviceResponse {
     #[serde(rename = "type")]
     pub(crate) device_type: String,
@@ -58,11 +130,43 @@ pub struct DeviceResponse {
 }
 
 impl DeviceResponse {
+    /// The `type` expected with a device pairing request. Like the rest of
+    /// the API, this has been inferred rather than documented.
+    pub(crate) const DEVICE_TYPE: &'static str = "device";
+
     /// Get the reported device ID. This is primarily used to confirm the device
     /// has been saved.
     pub fn id(&self) -> &str {
         &self.id
     }
+
+    /// Get the start of the device ID, for display.
+    pub fn short_id(&self) -> &str {
+        shorten_id(&self.id)
+    }
+
+    /// Get the reported message type. For a device pairing request, this is
+    /// expected to be `"device"`.
+    pub fn device_type(&self) -> &str {
+        &self.device_type
+    }
+
+    /// Get whether the device was confirmed as already saved, if it's been
+    /// confirmed with `TransferClient::confirm_device`. The API doesn't send
+    /// this, so it's `None` for a response that was only received.
+    pub fn is_saved(&self) -> Option<bool> {
+        self.is_saved
+    }
+
+    /// Checks this is actually a device pairing request, as any message with
+    /// the same fields would be parsed as one.
+    pub(crate) fn check_type(&self) -> crate::Result<()> {
+        if self.device_type == Self::DEVICE_TYPE {
+            Ok(())
+        } else {
+            Err(crate::error::ApiError::MalformedResponse)
+        }
+    }
 }
 
 /// Represents the LAN URL for the user's device.
@@ -72,13 +176,36 @@ impl DeviceResponse {
 /// connect in a future session.
 ///
 #[derive(Debug, Serialize, Deserialize)]
-pub(crate) struct LanUrlResponse {
+pub struct LanUrlResponse {
     pub(crate) url_lan: String,
     pub(crate) push_token: Option<Device>,
 }
 
+impl LanUrlResponse {
+    /// Get the URL of the device on the local network.
+    pub fn url_lan(&self) -> &str {
+        &self.url_lan
+    }
+
+    /// Get the push token, if the device requested to be saved.
+    pub fn push_token(&self) -> Option<&Device> {
+        self.push_token.as_ref()
+    }
+}
+
 // ------ API Requests ------
 
+/// Payload confirming a device, sent back over the websocket. This mirrors
+/// `DeviceResponse` with `is_saved` filled in.
+#[derive(Debug, Serialize)]
+pub(crate) struct DeviceConfirmation<'a> {
+    #[serde(rename = "type")]
+    pub device_type: &'a str,
+    #[serde(rename = "device")]
+    pub id: &'a str,
+    pub is_saved: bool,
+}
+
 /// Request payload for /api/v0/request-device.
 #[derive(Debug, Serialize)]
 pub(crate) struct SpecificDeviceRequest {
@@ -88,16 +215,25 @@ pub(crate) struct SpecificDeviceRequest {
 
 // ------ Device API Responses ------
 
-// Meta-information returned from the device.
-#[derive(Debug, Deserialize)]
+/// Meta-information returned from the device.
+///
+/// This is normally fetched when pairing, but can be built by hand for
+/// `device::MockDevice` with the `mock` feature.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-// Allowing since this is relevant API schema data, even if we aren't using it
-// right now.
-#[allow(dead_code)]
-pub(crate) struct DeviceInfo {
-    pub(crate) device_name: String,
-    pub(crate) known_file_extensions: Vec<String>,
-    pub(crate) supported_mimetypes: Vec<String>,
-    pub(crate) app_name: String,
-    pub(crate) app_version: u32,
+pub struct DeviceInfo {
+    pub device_name: String,
+    pub known_file_extensions: Vec<String>,
+    pub supported_mimetypes: Vec<String>,
+    pub app_name: String,
+    pub app_version: u32,
+}
+
+// Response from the device after uploading a file. The device hasn't been
+// observed sending a checksum, but this is accepted under the likely names in
+// case it does.
+#[derive(Debug, Deserialize)]
+pub(crate) struct UploadResponse {
+    #[serde(alias = "sha256")]
+    pub(crate) checksum: Option<String>,
 }
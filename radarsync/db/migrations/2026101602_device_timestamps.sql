@@ -0,0 +1,4 @@
+-- Unix timestamps, in seconds. Devices saved before this migration have no
+-- creation time.
+ALTER TABLE devices ADD COLUMN created_at INTEGER;
+ALTER TABLE devices ADD COLUMN last_synced INTEGER;
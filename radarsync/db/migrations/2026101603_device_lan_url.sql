@@ -0,0 +1,3 @@
+-- The URL the device was last reached at on the local network, so it can be
+-- tried directly before sending a push notification.
+ALTER TABLE devices ADD COLUMN lan_url TEXT;
@@ -0,0 +1,9 @@
+-- File names uploaded to each device, to warn before uploading one again.
+-- The device's own library can't be listed, so this only knows about uploads
+-- made from this machine.
+CREATE TABLE uploads (
+    device_id TEXT NOT NULL,
+    filename TEXT NOT NULL,
+    uploaded_at INTEGER NOT NULL,
+    PRIMARY KEY (device_id, filename)
+);
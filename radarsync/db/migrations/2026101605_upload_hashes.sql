@@ -0,0 +1,4 @@
+-- SHA-256 of each uploaded file's contents, to skip the same file under
+-- another name. Uploads recorded before this migration have no hash.
+ALTER TABLE uploads ADD COLUMN sha256 TEXT;
+CREATE INDEX uploads_device_sha256 ON uploads (device_id, sha256);
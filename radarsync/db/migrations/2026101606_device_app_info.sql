@@ -0,0 +1,4 @@
+-- What the device last reported about itself when synced to, to notice app
+-- updates between syncs. Devices not synced since this migration have none.
+ALTER TABLE devices ADD COLUMN device_name TEXT;
+ALTER TABLE devices ADD COLUMN app_version INTEGER;
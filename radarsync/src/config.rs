@@ -0,0 +1,68 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Command;
+use serde::Deserialize;
+
+/// Persistent defaults for command line arguments, loaded from
+/// `config.toml` in the radarsync config directory.
+///
+/// Each key mirrors the CLI flag of the same name. Arguments given on the
+/// command line always take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tasks: Option<u8>,
+    pub device: Option<String>,
+    pub recurse: Option<bool>,
+    pub progress: Option<String>,
+    pub no_qr: Option<bool>,
+    pub retries: Option<u8>,
+}
+
+impl Config {
+    /// Gets the path to the config file, if the config directory is known.
+    pub fn path() -> Option<PathBuf> {
+        let mut path = dirs::config_dir()?;
+        path.push("radarsync");
+        path.push("config.toml");
+        Some(path)
+    }
+
+    /// Loads the config file. If there isn't one, the default (empty) config
+    /// is returned.
+    pub fn load() -> anyhow::Result<Self> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        let text = match std::fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self::default());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("Error reading {}", path.display()));
+            }
+        };
+        toml::from_str(&text).with_context(|| format!("Error parsing {}", path.display()))
+    }
+
+    /// Applies the configured values as argument defaults. Values are passed
+    /// to clap as strings, so they're validated the same way as the CLI flags.
+    pub fn apply(&self, mut cmd: Command) -> Command {
+        fn set_default(cmd: Command, id: &'static str, value: Option<String>) -> Command {
+            match value {
+                Some(value) => cmd.mut_arg(id, |arg| arg.default_value(value)),
+                None => cmd,
+            }
+        }
+
+        cmd = set_default(cmd, "tasks", self.tasks.map(|v| v.to_string()));
+        cmd = set_default(cmd, "device", self.device.clone());
+        cmd = set_default(cmd, "recurse", self.recurse.map(|v| v.to_string()));
+        cmd = set_default(cmd, "progress", self.progress.clone());
+        cmd = set_default(cmd, "no_qr", self.no_qr.map(|v| v.to_string()));
+        cmd = set_default(cmd, "retries", self.retries.map(|v| v.to_string()));
+        cmd
+    }
+}
@@ -1,6 +1,35 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
 use anyhow::{bail, Context};
-use doppler_ws::model::Device;
-use sqlx::SqlitePool;
+use doppler_ws::{
+    model::{Device, DeviceInfo},
+    Uuid,
+};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions};
+
+/// Maximum number of connections in the library database pool.
+const MAX_CONNECTIONS: u32 = 4;
+
+/// How long a connection waits for a lock on the database before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A saved device, along with when it was saved and last used.
+#[derive(Debug)]
+pub struct DeviceRecord {
+    pub name: String,
+    /// Unix timestamp of when the device was saved, if known.
+    pub created_at: Option<i64>,
+    /// Unix timestamp of the last successful sync to the device.
+    pub last_synced: Option<i64>,
+    /// The name the device reported at the last sync.
+    pub device_name: Option<String>,
+    /// The app version the device reported at the last sync.
+    pub app_version: Option<i64>,
+}
 
 pub struct Library {
     db: sqlx::sqlite::SqlitePool,
@@ -28,7 +57,15 @@ impl Library {
             let db_url = format!("sqlite://{db_path_str}?mode=rwc");
             tracing::debug!("Opening database {db_url}");
 
-            SqlitePool::connect(&db_url).await?
+            // WAL and a busy timeout keep concurrent writers from failing
+            // with "database is locked"
+            let connect_options = SqliteConnectOptions::from_str(&db_url)?
+                .journal_mode(SqliteJournalMode::Wal)
+                .busy_timeout(BUSY_TIMEOUT);
+            SqlitePoolOptions::new()
+                .max_connections(MAX_CONNECTIONS)
+                .connect_with(connect_options)
+                .await?
         };
 
         sqlx::migrate!("db/migrations").run(&db).await?;
@@ -36,21 +73,45 @@ impl Library {
         Ok(Self { db })
     }
 
-    /// Gets a list of saved device names.
-    pub async fn device_names(&self) -> anyhow::Result<Vec<String>> {
-        use sqlx::Row;
+    /// Gets the ID this machine uses when connecting to the Doppler API,
+    /// generating and saving one on first use.
+    pub async fn client_id(&self) -> anyhow::Result<Uuid> {
         let mut conn = self.db.acquire().await?;
-        match sqlx::query("SELECT name FROM devices")
-            .fetch_all(conn.as_mut())
+        match sqlx::query!("SELECT value FROM settings WHERE key = 'client_id'")
+            .fetch_one(conn.as_mut())
             .await
         {
-            Ok(res) => Ok(res
-                .into_iter()
-                .filter_map(|m| m.try_get("name").ok())
-                .collect()),
-            Err(sqlx::Error::RowNotFound) => Ok(Vec::new()),
-            Err(err) => Err(err.into()),
+            Ok(res) => {
+                return Uuid::parse_str(&res.value).context("Saved client ID is invalid");
+            }
+            Err(sqlx::Error::RowNotFound) => {}
+            Err(err) => {
+                return Err(err.into());
+            }
         }
+
+        let client_id = Uuid::new_v4();
+        let id_str = client_id.to_string();
+        sqlx::query!(
+            "INSERT INTO settings (key, value) VALUES ('client_id', ?)",
+            id_str,
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(client_id)
+    }
+
+    /// Gets all saved devices, most recently synced first.
+    pub async fn devices(&self) -> anyhow::Result<Vec<DeviceRecord>> {
+        let mut conn = self.db.acquire().await?;
+        let devices = sqlx::query_as!(
+            DeviceRecord,
+            "SELECT name, created_at, last_synced, device_name, app_version FROM devices
+            ORDER BY last_synced IS NULL, last_synced DESC, name"
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(devices)
     }
 
     /// Gets a saved device with the provided name.
@@ -103,11 +164,117 @@ impl Library {
         };
         let mut conn = self.db.acquire().await?;
         let device_str = serde_json::to_string(device)?;
+        let now = unix_now();
         sqlx::query!(
-            "INSERT INTO devices (id, name, data) VALUES (?, ?, ?)",
+            "INSERT INTO devices (id, name, data, created_at) VALUES (?, ?, ?, ?)",
             device_id,
             device_name,
             device_str,
+            now,
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+
+    /// Changes the name of the saved device with the given ID, such as after
+    /// it was renamed on the device itself. Fails if another saved device
+    /// already has the name.
+    pub async fn rename_device(&self, id: impl AsRef<str>, name: &str) -> anyhow::Result<()> {
+        let id = id.as_ref();
+        let mut tx = self.db.begin().await?;
+        let taken = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM devices WHERE name = ? AND id != ?",
+            name,
+            id
+        )
+        .fetch_one(tx.as_mut())
+        .await?;
+        if taken > 0 {
+            bail!("Another saved device is named {name}");
+        }
+
+        // The name is also kept in the saved device record
+        let data = sqlx::query_scalar!("SELECT data FROM devices WHERE id = ?", id)
+            .fetch_one(tx.as_mut())
+            .await?;
+        let mut device: Device = serde_json::from_str(&data)?;
+        device.name = Some(name.to_string());
+        let data = serde_json::to_string(&device)?;
+        sqlx::query!(
+            "UPDATE devices SET name = ?, data = ? WHERE id = ?",
+            name,
+            data,
+            id
+        )
+        .execute(tx.as_mut())
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    /// Gets the LAN URL the device with the given ID was last reached at, if
+    /// known.
+    pub async fn lan_url(&self, id: impl AsRef<str>) -> anyhow::Result<Option<String>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let url = sqlx::query_scalar!("SELECT lan_url FROM devices WHERE id = ?", id)
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(url.flatten())
+    }
+
+    /// Records the LAN URL the device with the given ID was reached at.
+    pub async fn set_lan_url(&self, id: impl AsRef<str>, url: &str) -> anyhow::Result<()> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        sqlx::query!("UPDATE devices SET lan_url = ? WHERE id = ?", url, id)
+            .execute(conn.as_mut())
+            .await?;
+        Ok(())
+    }
+
+    /// Gets when the device with the given ID was last synced to, as a Unix
+    /// timestamp, if it's saved and has been synced to.
+    pub async fn last_synced(&self, id: impl AsRef<str>) -> anyhow::Result<Option<i64>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let synced = sqlx::query_scalar!("SELECT last_synced FROM devices WHERE id = ?", id)
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(synced.flatten())
+    }
+
+    /// Gets the name the device with the given ID reported about itself at
+    /// the last sync, if it's saved and has been synced to.
+    pub async fn reported_name(&self, id: impl AsRef<str>) -> anyhow::Result<Option<String>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let name = sqlx::query_scalar!("SELECT device_name FROM devices WHERE id = ?", id)
+            .fetch_optional(conn.as_mut())
+            .await?;
+        Ok(name.flatten())
+    }
+
+    /// Records that the device with the given ID was synced to, with the
+    /// files as they were at `at`, along with the info it reported.
+    pub async fn mark_synced(
+        &self,
+        id: impl AsRef<str>,
+        info: &DeviceInfo,
+        at: SystemTime,
+    ) -> anyhow::Result<()> {
+        let id = id.as_ref();
+        let at = at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        let mut conn = self.db.acquire().await?;
+        sqlx::query!(
+            "UPDATE devices SET last_synced = ?, device_name = ?, app_version = ? WHERE id = ?",
+            at,
+            info.device_name,
+            info.app_version,
+            id
         )
         .execute(conn.as_mut())
         .await?;
@@ -116,10 +283,92 @@ impl Library {
 
     pub async fn delete_device(&self, name: impl Into<String>) -> anyhow::Result<()> {
         let name = name.into();
-        let mut conn = self.db.acquire().await?;
+        let mut tx = self.db.begin().await?;
+        sqlx::query!(
+            "DELETE FROM uploads WHERE device_id IN (SELECT id FROM devices WHERE name = ?)",
+            name
+        )
+        .execute(tx.as_mut())
+        .await?;
         sqlx::query!("DELETE FROM devices WHERE name = ?", name)
-            .execute(conn.as_mut())
+            .execute(tx.as_mut())
             .await?;
+        tx.commit().await?;
         Ok(())
     }
+
+    /// Gets the names of the files uploaded to the device with the given ID.
+    pub async fn uploaded_names(&self, id: impl AsRef<str>) -> anyhow::Result<HashSet<String>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let names = sqlx::query_scalar!("SELECT filename FROM uploads WHERE device_id = ?", id)
+            .fetch_all(conn.as_mut())
+            .await?;
+        Ok(names.into_iter().collect())
+    }
+
+    /// Gets the names of the files uploaded to the device with the given ID,
+    /// along with the SHA-256 of their contents if it was recorded.
+    pub async fn uploaded_files(
+        &self,
+        id: impl AsRef<str>,
+    ) -> anyhow::Result<HashMap<String, Option<String>>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let files = sqlx::query!(
+            "SELECT filename, sha256 FROM uploads WHERE device_id = ?",
+            id
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(files
+            .into_iter()
+            .map(|file| (file.filename, file.sha256))
+            .collect())
+    }
+
+    /// Gets the SHA-256 of the contents of the files uploaded to the device
+    /// with the given ID.
+    pub async fn uploaded_hashes(&self, id: impl AsRef<str>) -> anyhow::Result<HashSet<String>> {
+        let id = id.as_ref();
+        let mut conn = self.db.acquire().await?;
+        let hashes = sqlx::query_scalar!(
+            "SELECT sha256 FROM uploads WHERE device_id = ? AND sha256 IS NOT NULL",
+            id
+        )
+        .fetch_all(conn.as_mut())
+        .await?;
+        Ok(hashes.into_iter().flatten().collect())
+    }
+
+    /// Records that a file with the given name and SHA-256 was uploaded to
+    /// the device with the given ID.
+    pub async fn record_upload(
+        &self,
+        id: impl AsRef<str>,
+        filename: &str,
+        sha256: &str,
+    ) -> anyhow::Result<()> {
+        let id = id.as_ref();
+        let now = unix_now();
+        let mut conn = self.db.acquire().await?;
+        sqlx::query!(
+            "INSERT OR REPLACE INTO uploads (device_id, filename, uploaded_at, sha256)
+            VALUES (?, ?, ?, ?)",
+            id,
+            filename,
+            now,
+            sha256,
+        )
+        .execute(conn.as_mut())
+        .await?;
+        Ok(())
+    }
+}
+
+/// Gets the current time as a Unix timestamp.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
 }
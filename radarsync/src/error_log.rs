@@ -0,0 +1,45 @@
+//! Recording of failed uploads to a JSON Lines file.
+
+use std::{fs::File, io::Write, path::Path, sync::Mutex, time::SystemTime};
+
+use anyhow::Context;
+use doppler_ws::error::ApiError;
+
+/// A file that failed uploads are appended to, one JSON object per line.
+pub struct ErrorLog {
+    file: Mutex<File>,
+}
+
+impl ErrorLog {
+    /// Opens the log for appending, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Error opening error log {}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Records a failed upload, with the error's code if it came from the
+    /// device. Failing to write the entry is only logged, as it shouldn't
+    /// stop the sync.
+    pub fn record(&self, path: &Path, err: &anyhow::Error) {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let code = err.downcast_ref::<ApiError>().map(ApiError::code);
+        let entry = serde_json::json!({
+            "path": path.to_string_lossy(),
+            "error": format!("{err:#}"),
+            "code": code,
+            "timestamp": timestamp,
+        });
+        let mut file = self.file.lock().unwrap();
+        if let Err(err) = writeln!(file, "{entry}") {
+            tracing::warn!("Couldn't write to error log: {err}");
+        }
+    }
+}
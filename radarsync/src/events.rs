@@ -0,0 +1,55 @@
+//! Events describing how uploads are going, so they can be shown without the
+//! upload code knowing how.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use tokio::sync::mpsc;
+
+/// Identifies a single upload, of a file or a batch of files, across its
+/// events.
+pub type UploadId = usize;
+
+/// Sends the events of a run of uploads.
+pub type EventSender = mpsc::UnboundedSender<SyncEvent>;
+
+/// Something that happened while uploading.
+#[derive(Debug)]
+pub enum SyncEvent {
+    /// Files are being looked for in `dir`, with `found` found there so far.
+    Scanning { dir: Arc<Path>, found: usize },
+    /// Uploading is starting, with the number of files and their combined
+    /// size, if known.
+    Selected { files: usize, bytes: Option<u64> },
+    /// An upload started, or started again after being requeued.
+    ///
+    /// `name` is what the device is sent, or a description of a batch.
+    /// `path` is the file being sent, if the upload is of a single file.
+    FileStarted {
+        id: UploadId,
+        name: String,
+        path: Option<PathBuf>,
+    },
+    /// An upload failed in a way worth retrying, and will be started again
+    /// once the others have been tried. The bytes it sent no longer count.
+    FileRequeued { id: UploadId },
+    /// Some of an upload's bytes were sent.
+    FileProgress { id: UploadId, bytes: u64 },
+    /// An upload of `files` files, totaling `bytes`, finished.
+    FileDone {
+        id: UploadId,
+        files: u64,
+        bytes: u64,
+    },
+    /// An upload of `files` files, totaling `bytes`, failed or was skipped.
+    FileFailed {
+        id: UploadId,
+        files: u64,
+        bytes: u64,
+    },
+    /// Uploading ended. `complete` is false if it stopped early because of
+    /// an error.
+    Done { complete: bool },
+}
@@ -0,0 +1,112 @@
+//! Commands run before and after each upload, for --pre-upload-cmd and
+//! --post-upload-cmd.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+
+use anyhow::{bail, Context};
+use tokio::process::Command;
+
+/// Environment variable holding the path of the file a command is run for.
+const FILE_VAR: &str = "RADARSYNC_FILE";
+
+/// The commands to run around each upload, if any.
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pre: Option<String>,
+    post: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(pre: Option<String>, post: Option<String>) -> Self {
+        Self { pre, post }
+    }
+
+    /// Runs the pre-upload command for the file at `path`. If the command
+    /// prints a path as the last line of its output, that file is returned to
+    /// be uploaded instead.
+    pub async fn before(&self, path: &Path) -> anyhow::Result<Option<PathBuf>> {
+        let Some(cmd) = &self.pre else {
+            return Ok(None);
+        };
+        let output = run(cmd, path).await.context("pre-upload command failed")?;
+        let output = String::from_utf8_lossy(&output);
+        let Some(line) = output.lines().map(str::trim).rfind(|line| !line.is_empty()) else {
+            return Ok(None);
+        };
+        let source = PathBuf::from(line);
+        if !source.is_file() {
+            bail!(
+                "pre-upload command printed {}, which isn't a file",
+                source.display()
+            );
+        }
+        tracing::debug!("{}: uploading {} instead", path.display(), source.display());
+        Ok(Some(source))
+    }
+
+    /// Runs the post-upload command for the file at `path` once it's been
+    /// uploaded. Failures are only logged, as the upload itself worked.
+    pub async fn after(&self, path: &Path) {
+        let Some(cmd) = &self.post else {
+            return;
+        };
+        if let Err(err) = run(cmd, path).await {
+            tracing::warn!("{}: post-upload command failed: {err:#}", path.display());
+        }
+    }
+}
+
+/// Runs `cmd` through the shell with each `{}` replaced by the quoted `path`,
+/// returning what it printed. The path is also set in [`FILE_VAR`].
+async fn run(cmd: &str, path: &Path) -> anyhow::Result<Vec<u8>> {
+    let cmd = cmd.replace("{}", &quote(path));
+    tracing::debug!("Running {cmd}");
+    let output = shell(&cmd)
+        .env(FILE_VAR, path)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .with_context(|| format!("couldn't run {cmd}"))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        match stderr.trim() {
+            "" => bail!("{}", output.status),
+            stderr => bail!("{}: {stderr}", output.status),
+        }
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(not(windows))]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(cmd);
+    command
+}
+
+/// The command line is passed to `cmd` as is, since it parses quotes itself
+/// rather than following the escaping `arg` would add.
+#[cfg(windows)]
+fn shell(cmd: &str) -> Command {
+    let mut command = Command::new("cmd");
+    command.arg("/C").raw_arg(cmd);
+    command
+}
+
+/// Quotes a path so the shell passes it through as a single argument.
+#[cfg(not(windows))]
+fn quote(path: &Path) -> String {
+    format!("'{}'", path.to_string_lossy().replace('\'', r"'\''"))
+}
+
+/// Quotes a path so the shell passes it through as a single argument.
+/// Windows paths can't contain `"`, so no escaping is needed for that, but
+/// `cmd` still expands `%VAR%` inside the quotes, so a path containing `%`
+/// may not come through as is. Commands can read [`FILE_VAR`] instead.
+#[cfg(windows)]
+fn quote(path: &Path) -> String {
+    format!("\"{}\"", path.display())
+}
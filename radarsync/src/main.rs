@@ -1,23 +1,85 @@
+mod config;
 mod db;
+mod error_log;
+mod events;
+mod hooks;
 mod progress;
+mod prune;
+mod select;
+mod selftest;
+mod tags;
+mod template;
+mod tuning;
+mod watch;
 
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
     io::IsTerminal,
     path::{Path, PathBuf},
     process::ExitCode,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{bail, Context};
-use clap::{Parser, ValueEnum};
-use db::Library;
-use doppler_ws::device::DeviceClient;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use config::Config;
+use db::{DeviceRecord, Library};
+#[cfg(feature = "batch")]
+use doppler_ws::device::MAX_BATCH_FILES;
+use doppler_ws::{
+    backoff::Backoff,
+    device::{
+        DeviceClient, DeviceClientBuilder, HttpVersion, NameNormalization, PairedDevice,
+        ProgressFn, ProgressThrottle, Upload, UploadOutcome,
+    },
+    error::ApiError,
+    model::Device,
+    HeaderValue, TransferClient, TransferClientBuilder,
+};
+use error_log::ErrorLog;
+use events::{EventSender, SyncEvent, UploadId};
+use hooks::Hooks;
+use indicatif::{HumanBytes, HumanDuration};
 use mime_guess::Mime;
-use progress::Progression;
-use tokio::sync::{mpsc, OwnedSemaphorePermit, Semaphore};
-use tracing::level_filters::LevelFilter;
+use progress::{BarStyle, Progression};
+use qrencode::render::unicode;
+use rayon::prelude::*;
+use select::{
+    read_manifest, CommonTypes, Filters, MimeOverrides, PathOutcome, Routes, SelectedFile,
+    Selection, SkipReason, TypeCount,
+};
+use template::NameTemplate;
+use terminal_size::Width;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{level_filters::LevelFilter, Instrument};
+use tuning::AutoTasks;
+
+/// The largest fraction retry delays are randomly shortened by, so devices
+/// synced to at once aren't all retried at once.
+const RETRY_JITTER: f64 = 0.2;
+
+/// How many times to retry connecting to the pairing API on transient errors.
+const CONNECT_RETRIES: u32 = 3;
+
+/// How long to wait for a saved device before offering to resend the push
+/// notification.
+const PUSH_RESEND_AFTER: Duration = Duration::from_secs(60);
+
+/// Largest file grouped with others into a single request by --batch-size.
+const BATCH_FILE_SIZE: u64 = 1024 * 1024;
+
+/// How long to wait to reach a saved device at its last known LAN URL before
+/// pairing through the API instead.
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Shortest time between progress updates from an upload, so the progress bar
+/// isn't updated for every chunk sent.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Identifies radarsync to the pairing API and devices.
+const USER_AGENT: &str = concat!("radarsync/", env!("CARGO_PKG_VERSION"));
 
 #[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 enum ProgressMode {
@@ -42,6 +104,81 @@ impl fmt::Display for ProgressMode {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human-readable text.
+    #[default]
+    Text,
+    /// JSON, for use by scripts.
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        }
+        .fmt(f)
+    }
+}
+
+/// Unicode normalization for uploaded file names.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum Normalization {
+    /// Send names as they are.
+    #[default]
+    None,
+    /// Composed form, which the device expects.
+    Nfc,
+    /// Decomposed form.
+    Nfd,
+}
+
+impl fmt::Display for Normalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => "none",
+            Self::Nfc => "nfc",
+            Self::Nfd => "nfd",
+        }
+        .fmt(f)
+    }
+}
+
+impl From<Normalization> for NameNormalization {
+    fn from(value: Normalization) -> Self {
+        match value {
+            Normalization::None => Self::None,
+            Normalization::Nfc => Self::Nfc,
+            Normalization::Nfd => Self::Nfd,
+        }
+    }
+}
+
+/// What to do with a file uploaded under the same name as another.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum OnConflict {
+    /// Upload it under the same name anyway.
+    #[default]
+    Overwrite,
+    /// Don't upload it.
+    Skip,
+    /// Upload it with a number added to its name, such as "track01 (2).mp3".
+    Rename,
+}
+
+impl fmt::Display for OnConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Overwrite => "overwrite",
+            Self::Skip => "skip",
+            Self::Rename => "rename",
+        }
+        .fmt(f)
+    }
+}
+
 /// Utility to transfer music to Doppler for iOS
 #[derive(Parser, Debug)]
 #[command(version, about)]
@@ -63,37 +200,451 @@ struct Args {
     /// How to display upload progress
     #[arg(long, default_value_t)]
     progress: ProgressMode,
-    /// Number of upload tasks to run simultaneously
+    /// The look of the upload progress bar
+    #[arg(long, default_value_t)]
+    progress_style: BarStyle,
+    /// Number of uploads to run simultaneously on each device
+    ///
+    /// Lower this if the device stalls or drops uploads.
     #[arg(short, long, default_value_t = 5)]
     tasks: u8,
-    /// Sync to a saved device
-    #[arg(short, long)]
-    device: Option<String>,
+    /// Most uploads to run simultaneously across all devices
+    ///
+    /// Use this to keep from saturating the network. Each upload needs a slot
+    /// from both --tasks and this, so with a single device the lower of the
+    /// two applies. Unlimited by default.
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..))]
+    global_tasks: Option<u8>,
+    /// Adjust the number of simultaneous uploads to the network
+    ///
+    /// Uploads start two at a time. More are allowed while that keeps
+    /// throughput up, up to --tasks, and fewer after an upload fails or slows
+    /// down. This helps on unreliable Wi-Fi. Use -v to see the adjustments.
+    #[arg(long)]
+    auto_tasks: bool,
+    /// Upload files up to 1 MiB this many at a time, in a single request each
+    ///
+    /// This can speed up syncing many short tracks, but it's not known whether
+    /// the device accepts more than one file per request. Check that every
+    /// file arrived before relying on it. Files sent together aren't recorded
+    /// as uploaded, so later syncs don't skip them.
+    #[cfg(feature = "batch")]
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=MAX_BATCH_FILES as i64),
+        conflicts_with_all = ["pre_upload_cmd", "post_upload_cmd"]
+    )]
+    batch_size: u8,
+    /// Number of times to retry an upload that failed with a transient error
+    #[arg(long, default_value_t = 0)]
+    retries: u8,
+    /// Delay before the first retry, which doubles with each retry after
+    ///
+    /// Applies to uploads and to connecting to the pairing API. Accepts a
+    /// duration such as 500ms, 2s or 1m.
+    #[arg(long, default_value = "1s", value_parser = parse_duration)]
+    retry_base: Duration,
+    /// Longest delay before any retry
+    ///
+    /// Accepts a duration such as 30s or 5m.
+    #[arg(long, default_value = "30s", value_parser = parse_duration)]
+    retry_max: Duration,
+    /// Sync to a saved device. Can be given more than once
+    ///
+    /// If not given, the device named by RADARSYNC_DEVICE is used, if set.
+    /// Devices that can't be paired are skipped with a warning, unless
+    /// --all-required is given.
+    #[arg(short, long, env = "RADARSYNC_DEVICE")]
+    device: Vec<String>,
+    /// Fail if any --device can't be paired, instead of syncing to the rest
+    #[arg(long)]
+    all_required: bool,
+    /// Choose a saved device from a list when no --device is given
+    ///
+    /// The list also has an option to pair a new device with a code. This is
+    /// only shown when running in a terminal.
+    #[arg(long)]
+    interactive: bool,
     /// List all saved devices
     #[arg(long, conflicts_with = "paths")]
     list_devices: bool,
     /// Forget the named device
     #[arg(long, conflicts_with = "paths")]
     drop_device: Option<String>,
+    /// Send each saved device a notification, and forget those that don't
+    /// respond
+    ///
+    /// Devices reachable at their last LAN URL aren't sent a notification.
+    /// Each device has 30 seconds to respond.
+    #[arg(long, conflicts_with = "paths")]
+    prune_devices: bool,
+    /// With --prune-devices, only list the devices that would be forgotten
+    #[arg(long, requires = "prune_devices")]
+    dry_run: bool,
+    /// Pair with the device, print what it supports, and exit without uploading
+    #[arg(long, conflicts_with = "paths")]
+    probe: bool,
+    /// Check that the pairing API and library database can be reached, print
+    /// the results and versions, and exit
+    ///
+    /// This doesn't pair or upload. Include the output in bug reports.
+    #[arg(long, conflicts_with = "paths")]
+    selftest: bool,
+    /// Count the supported files by type and their total size, then exit
+    ///
+    /// This doesn't connect to a device, so a list of types Doppler commonly
+    /// supports is used. The device's own list may differ. Any --device, such
+    /// as from RADARSYNC_DEVICE, is ignored.
+    #[arg(long, conflicts_with = "probe")]
+    count_only: bool,
+    /// Pair, then upload supported files as they appear in DIR until stopped
+    ///
+    /// Files are uploaded once their size has stopped changing for a few
+    /// seconds, so downloads aren't sent half done. Subfolders are watched
+    /// with -r. Files already uploaded under the same name are skipped. If
+    /// the device stops responding, it's paired with again.
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["paths", "probe", "count_only"])]
+    watch: Option<PathBuf>,
+    /// Format of the information printed about the device
+    ///
+    /// With json, an event with the paired device's ID, name and LAN URL is
+    /// printed before uploading, an event with the number of files uploaded
+    /// by type is printed after, and --probe, --count-only and --selftest
+    /// print JSON.
+    #[arg(long, default_value_t)]
+    output: OutputFormat,
     /// Disable the QR Code display
     #[arg(long)]
     no_qr: bool,
+    /// Skip files larger than this size
+    ///
+    /// Accepts a number of bytes, or a size with a K, M, G or T suffix (powers
+    /// of 1024), such as 500M or 2G.
+    #[arg(long, value_parser = parse_size)]
+    max_file_size: Option<u64>,
+    /// Only upload files modified after this time
+    ///
+    /// Accepts a duration before now, such as 24h or 7d (with s, m, h, d or w
+    /// units), or a date in the form YYYY-MM-DD, taken as midnight UTC.
+    #[arg(long, value_parser = parse_cutoff)]
+    modified_since: Option<SystemTime>,
+    /// Only upload files modified since the last sync to the device
+    ///
+    /// Uses the time the last fully successful sync to the saved device
+    /// started. Has no effect on devices that were never synced to.
+    #[arg(long)]
+    since_last_sync: bool,
+    /// Only accept files whose type is listed exactly by the device
+    ///
+    /// By default, a type such as audio/flac is also accepted if the device
+    /// lists audio/x-flac.
+    #[arg(long)]
+    strict_mime: bool,
+    /// Upload every file as this MIME type instead of guessing from its name
+    ///
+    /// The device must still support the type. Every file found is uploaded
+    /// with it, so only give paths to files of a single format.
+    #[arg(long, value_name = "TYPE", value_parser = parse_mime)]
+    force_mime: Option<Mime>,
+    /// Upload files with an extension as a MIME type, such as dsf=audio/dsf
+    ///
+    /// Can be given more than once. Other files' types are still guessed, and
+    /// the device must still support the type.
+    #[arg(long, value_name = "EXT=TYPE", value_parser = parse_mime_mapping)]
+    mime_map: Vec<(String, Mime)>,
+    /// Upload files with an extension to a saved device, such as flac=phone
+    ///
+    /// Can be given more than once. Every device routed to is synced with
+    /// the files routed to it. Other files go to the devices given with
+    /// --device, or are skipped if there are none.
+    #[arg(
+        long,
+        value_name = "EXT=DEVICE",
+        value_parser = parse_route,
+        conflicts_with_all = ["watch", "count_only"]
+    )]
+    route: Vec<(String, String)>,
+    /// Fail if any file found in a directory or glob pattern isn't supported
+    ///
+    /// By default these are skipped. Every unsupported file is listed before
+    /// failing. Files left out by filters such as --max-file-size are still
+    /// skipped.
+    #[arg(long)]
+    strict: bool,
+    /// Skip files without artist and title tags
+    ///
+    /// Doppler sorts the library by these tags, so untagged files show up as
+    /// "Unknown Artist". Files whose tags can't be read are also skipped.
+    #[arg(long)]
+    require_tags: bool,
+    /// Unicode normalization to apply to file names before uploading
+    ///
+    /// Use nfc if names exported from macOS show up mangled on the device.
+    #[arg(long, default_value_t)]
+    normalize_names: Normalization,
+    /// Name files on the device from a template instead of their file name
+    ///
+    /// Placeholders are {name} (the file name), {stem} (without the
+    /// extension), {ext}, {parent} (the containing folder) and {grandparent}.
+    /// For example, {parent}-{name} keeps tracks with the same name in
+    /// different album folders apart. The device doesn't keep folders, so
+    /// the template can't contain a '/'.
+    #[arg(long, value_parser = NameTemplate::parse)]
+    name_template: Option<NameTemplate>,
+    /// Add the uploaded files to this existing playlist on the device
+    ///
+    /// It's not known whether the device supports this. If it doesn't, the
+    /// files are uploaded to the library as usual.
+    #[cfg(feature = "playlists")]
+    #[arg(long)]
+    playlist: Option<String>,
+    /// Try HTTP/2 with the device, falling back to HTTP/1.1
+    ///
+    /// HTTP/2 lets concurrent uploads share one connection, but no device has
+    /// been seen supporting it, and trying it can slow down connecting.
+    #[arg(long)]
+    http2: bool,
+    /// Accept any certificate from devices that serve uploads over HTTPS
+    ///
+    /// Use this if a device with a self-signed certificate can't be reached.
+    /// Another machine on the network could then pose as the device, so only
+    /// use it on networks you trust. The pairing API's certificate is always
+    /// checked.
+    #[arg(long)]
+    accept_invalid_certs: bool,
+    /// Skip files with the same name as one already uploaded to the device
+    ///
+    /// Uploads are remembered per device on this machine, since the device's
+    /// library can't be listed. Without this, a warning is shown instead.
+    #[arg(long)]
+    skip_existing: bool,
+    /// What to do with files uploaded under the same name as another
+    ///
+    /// This covers files with the same name in one run, and ones with the
+    /// name of a file already uploaded to the device. With overwrite, they're
+    /// sent under the same name, which the device may keep as duplicates.
+    #[arg(long, default_value_t)]
+    on_conflict: OnConflict,
+    /// Skip files whose contents were already uploaded to the device
+    ///
+    /// Every file is hashed before uploading, which takes longer. Files with
+    /// the same contents are treated as duplicates even under another name,
+    /// including within a single run.
+    #[arg(long)]
+    dedup_content: bool,
+    /// Fail uploads whose checksum doesn't match the one reported by the device
+    ///
+    /// This has no effect if the device doesn't report checksums.
+    #[arg(long)]
+    verify: bool,
+    /// Append each failed upload to this file as a line of JSON
+    ///
+    /// Each line has the path, error and Unix timestamp of the failure.
+    #[arg(long)]
+    error_log: Option<PathBuf>,
+    /// Run a command on each file before uploading it
+    ///
+    /// The command is run by the shell, with {} replaced by the quoted path of
+    /// the file, which is also in RADARSYNC_FILE. If it prints the path of
+    /// another file as its last line of output, such as a transcoded copy,
+    /// that file is uploaded instead. If the command fails, so does the sync.
+    #[arg(long, value_name = "CMD")]
+    pre_upload_cmd: Option<String>,
+    /// Run a command on each file after it's uploaded
+    ///
+    /// This is run like --pre-upload-cmd, with the original file. Failures are
+    /// only warned about.
+    #[arg(long, value_name = "CMD")]
+    post_upload_cmd: Option<String>,
+    /// Don't load defaults from the config file
+    #[arg(long)]
+    no_config: bool,
+    /// Read paths to transfer from a file, one per line
+    ///
+    /// Blank lines and lines starting with # are ignored. Relative paths are
+    /// taken from the file's folder. These are added to any paths given on
+    /// the command line.
+    #[arg(
+        long,
+        value_name = "MANIFEST",
+        conflicts_with_all = [
+            "list_devices", "drop_device", "prune_devices", "probe", "selftest", "watch",
+        ]
+    )]
+    from_file: Option<PathBuf>,
     /// Paths to transfer to the device
-    #[arg(required = true)]
+    #[arg(required_unless_present_any = [
+        "from_file", "list_devices", "drop_device", "prune_devices", "probe", "selftest",
+        "watch",
+    ])]
     paths: Vec<PathBuf>,
 }
 
+/// Parses a file size such as `4096`, `500M` or `2GiB`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{value}'"))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        _ => return Err(format!("unknown size suffix '{suffix}'")),
+    };
+    number
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{value}' is too large"))
+}
+
+/// Parses a MIME type such as `audio/ogg`.
+fn parse_mime(value: &str) -> Result<Mime, String> {
+    let mime: Mime = value
+        .trim()
+        .parse()
+        .map_err(|_| format!("invalid MIME type '{value}', expected type/subtype"))?;
+    if mime.type_() == mime_guess::mime::STAR || mime.subtype() == mime_guess::mime::STAR {
+        return Err(format!("'{value}' is a wildcard, not a single type"));
+    }
+    Ok(mime)
+}
+
+/// Parses an extension and the MIME type to use for it, such as
+/// `opus=audio/ogg`. The extension is lowercased and may start with a dot.
+fn parse_mime_mapping(value: &str) -> Result<(String, Mime), String> {
+    let Some((ext, mime)) = value.split_once('=') else {
+        return Err(format!("expected EXT=TYPE, got '{value}'"));
+    };
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    if ext.is_empty() {
+        return Err(format!("no extension given in '{value}'"));
+    }
+    Ok((ext, parse_mime(mime)?))
+}
+
+/// Parses an extension and the saved device to upload files with it to, such
+/// as `flac=phone`. The extension is lowercased and may start with a dot.
+fn parse_route(value: &str) -> Result<(String, String), String> {
+    let Some((ext, device)) = value.split_once('=') else {
+        return Err(format!("expected EXT=DEVICE, got '{value}'"));
+    };
+    let ext = ext.trim().trim_start_matches('.').to_lowercase();
+    let device = device.trim();
+    if ext.is_empty() || device.is_empty() {
+        return Err(format!("expected EXT=DEVICE, got '{value}'"));
+    }
+    Ok((ext, device.to_string()))
+}
+
+/// Parses a duration such as `500ms`, `30s` or `7d`.
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    if let Some(amount) = value.strip_suffix("ms") {
+        return amount
+            .parse()
+            .map(Duration::from_millis)
+            .map_err(|_| format!("invalid duration '{value}'"));
+    }
+    let Some(unit) = value.chars().last().filter(char::is_ascii_alphabetic) else {
+        return Err(format!(
+            "'{value}' has no unit, expected one of ms, s, m, h, d or w"
+        ));
+    };
+    let amount: u64 = value[..value.len() - 1]
+        .parse()
+        .map_err(|_| format!("invalid duration '{value}'"))?;
+    let unit_secs = match unit {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit '{unit}'")),
+    };
+    amount
+        .checked_mul(unit_secs)
+        .map(Duration::from_secs)
+        .ok_or_else(|| format!("duration '{value}' is too long"))
+}
+
+/// Parses a cutoff time, either as a relative duration such as `7d` or a date
+/// such as `2024-08-27`.
+fn parse_cutoff(value: &str) -> Result<SystemTime, String> {
+    let value = value.trim();
+    if value.ends_with(|c: char| c.is_ascii_alphabetic()) {
+        return parse_duration(value).and_then(|duration| {
+            SystemTime::now()
+                .checked_sub(duration)
+                .ok_or_else(|| format!("duration '{value}' is too long"))
+        });
+    }
+
+    let invalid = || format!("invalid date '{value}', expected YYYY-MM-DD");
+    let mut parts = value.splitn(3, '-');
+    let mut next = || -> Result<i64, String> {
+        parts
+            .next()
+            .and_then(|p| p.parse().ok())
+            .ok_or_else(invalid)
+    };
+    let (year, month, day) = (next()?, next()?, next()?);
+    if !(1..=12).contains(&month) || !(1..=days_in_month(year, month)).contains(&day) {
+        return Err(invalid());
+    }
+
+    // Days since the Unix epoch, from Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let secs = u64::try_from(days * 24 * 60 * 60).map_err(|_| invalid())?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Gets the number of days in `month` (1 to 12) of `year`.
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
 fn init_args() -> Args {
-    let mut args = Args::parse();
+    // The config file provides defaults for the CLI, so it has to be loaded
+    // before the arguments are parsed
+    let mut cmd = Args::command();
+    if !std::env::args_os().any(|arg| arg == "--no-config") {
+        match Config::load() {
+            Ok(config) => cmd = config.apply(cmd),
+            Err(err) => cmd
+                .error(clap::error::ErrorKind::Io, format!("{err:#}"))
+                .exit(),
+        }
+    }
+    let mut args = Args::from_arg_matches(&cmd.get_matches()).unwrap_or_else(|err| err.exit());
 
     // The progress bar should be shown with 'auto' if:
-    // - stdout is a tty
+    // - stderr is a tty
     // - quiet is not set
-
-    if std::io::stderr().is_terminal() && !args.quiet {
-        args.progress = ProgressMode::On;
-    } else {
-        args.progress = ProgressMode::Off;
+    // An explicit 'on' or 'off' is left as given.
+    if args.progress == ProgressMode::Auto {
+        args.progress = if std::io::stderr().is_terminal() && !args.quiet {
+            ProgressMode::On
+        } else {
+            ProgressMode::Off
+        };
     }
 
     // Set the log level according to the arguments
@@ -127,220 +678,1605 @@ fn main() -> ExitCode {
         .block_on(async move { app_main(args).await })
     {
         tracing::error!("{err}");
+        if let Some(ApiError::DeviceBusy) = err.downcast_ref() {
+            tracing::error!("Close the other transfer on the device and try again.");
+        }
         ExitCode::FAILURE
     } else {
         ExitCode::SUCCESS
     }
 }
 
-async fn process_file<'a, P: AsRef<Path>>(
-    device: &DeviceClient,
+async fn process_file<P: AsRef<Path>>(
+    device: &impl Upload,
     mime: Mime,
-    path: &'a P,
-    _permit: OwnedSemaphorePermit,
-) -> anyhow::Result<()> {
+    path: &P,
+    name: &str,
+    on_progress: ProgressFn,
+) -> anyhow::Result<UploadOutcome> {
     tracing::info!("Uploading {}", path.as_ref().display());
-    let file = tokio::fs::File::open(path).await?;
+    let len = tokio::fs::metadata(path).await?.len();
+    let start = Instant::now();
+    tracing::debug!(bytes = len, "Upload started");
+    let outcome = device
+        .upload_file_as(path.as_ref(), name, mime, on_progress)
+        .await?;
+    tracing::debug!(
+        bytes = len,
+        elapsed = ?start.elapsed(),
+        sha256 = %outcome.sha256,
+        "Upload finished"
+    );
 
-    let meta = file.metadata().await?;
-    device.upload(path, meta.len(), mime, file).await?;
+    Ok(outcome)
+}
 
-    Ok(())
+/// A file uploaded by [`process_all_paths`].
+struct Uploaded {
+    /// The file selected for upload, before any pre-upload command swapped
+    /// in another.
+    path: PathBuf,
+    name: String,
+    sha256: String,
+    mime: Mime,
+    bytes: u64,
+    /// Whether the device is known to keep the file, so it's recorded as
+    /// uploaded. Files sent in a batch aren't, as the device may only keep
+    /// the first file of each batch.
+    confirmed: bool,
+}
+
+/// An upload of one file or a batch of files, waiting for its turn.
+struct Queued {
+    id: UploadId,
+    files: Vec<(PathBuf, String, Mime)>,
+    /// Times the upload has already failed and been requeued.
+    attempt: u8,
+}
+
+/// Whether a failed upload should be requeued, and if so, logs it and takes
+/// back its progress.
+fn requeue(
+    queued: &Queued,
+    err: &anyhow::Error,
+    settings: &UploadSettings,
+    events: &EventSender,
+    tuner: Option<&AutoTasks>,
+) -> bool {
+    let retries = settings.retries;
+    if queued.attempt >= retries || !is_retryable(err) {
+        return false;
+    }
+    if let Some(tuner) = tuner {
+        tuner.failure();
+    }
+    tracing::warn!(
+        "Retrying after the other uploads ({}/{retries}): {err:#}",
+        queued.attempt + 1
+    );
+    let _ = events.send(SyncEvent::FileRequeued { id: queued.id });
+    true
+}
+
+/// Uploads several small files in one request for --batch-size, sending each
+/// file uploaded, or the error if the batch failed. Returns the batch if it
+/// failed in a way worth retrying, to try again once the other uploads have
+/// been tried.
+#[cfg(feature = "batch")]
+async fn process_batch(
+    device: &DeviceClient,
+    queued: Queued,
+    sender: &mpsc::Sender<anyhow::Result<Uploaded>>,
+    settings: &UploadSettings,
+    events: &EventSender,
+    tuner: Option<&AutoTasks>,
+) -> Option<Queued> {
+    let Queued { id, ref files, .. } = queued;
+    let _ = events.send(SyncEvent::FileStarted {
+        id,
+        name: format!("{} files", files.len()),
+        path: None,
+    });
+    let result = send_batch(device, files)
+        .await
+        .with_context(|| format!("Error uploading to device at {}", device.address()));
+    if let Err(err) = &result {
+        if requeue(&queued, err, settings, events, tuner) {
+            return Some(queued);
+        }
+    }
+    if let Some(tuner) = tuner {
+        match &result {
+            Ok(uploaded) => tuner.success(uploaded.iter().map(|u| u.bytes).sum()),
+            Err(_) => tuner.failure(),
+        }
+    }
+
+    let files_len = files.len() as u64;
+    match result {
+        Ok(uploaded) => {
+            let bytes = uploaded.iter().map(|u| u.bytes).sum();
+            let _ = events.send(SyncEvent::FileDone {
+                id,
+                files: files_len,
+                bytes,
+            });
+            for uploaded in uploaded {
+                let _ = sender.send(Ok(uploaded)).await;
+            }
+        }
+        Err(err) => {
+            let mut bytes = 0;
+            for (path, _, _) in files {
+                if let Some(error_log) = &settings.error_log {
+                    error_log.record(path, &err);
+                }
+                bytes += tokio::fs::metadata(path).await.map_or(0, |meta| meta.len());
+            }
+            let _ = events.send(SyncEvent::FileFailed {
+                id,
+                files: files_len,
+                bytes,
+            });
+            let err = err.context(format!("batch of {}", files.len()));
+            tracing::debug!("Batch upload failed: {err:#}");
+            let _ = sender.send(Err(err)).await;
+        }
+    }
+    None
+}
+
+/// Uploads a single file, running the upload commands around it, and sends
+/// it once uploaded, or the error if it failed. Returns the file if it failed
+/// in a way worth retrying, to try again once the other uploads have been
+/// tried.
+async fn process_single(
+    device: &DeviceClient,
+    queued: Queued,
+    sender: &mpsc::Sender<anyhow::Result<Uploaded>>,
+    settings: &UploadSettings,
+    events: &EventSender,
+    tuner: Option<&AutoTasks>,
+) -> Option<Queued> {
+    let id = queued.id;
+    let (path, name, mime) = queued.files.first()?.clone();
+    let (source, name, mime) = match pre_upload(device, settings, &path, name, mime).await {
+        Ok(source) => source,
+        // Fails the sync like a failed upload, so it isn't recorded as
+        // complete with this file left out
+        Err(err) => {
+            if let Some(error_log) = &settings.error_log {
+                error_log.record(&path, &err);
+            }
+            let len = tokio::fs::metadata(&path)
+                .await
+                .map_or(0, |meta| meta.len());
+            let _ = events.send(SyncEvent::FileFailed {
+                id,
+                files: 1,
+                bytes: len,
+            });
+            tracing::error!("{}: {err:#}", path.display());
+            let err = err.context(path.display().to_string());
+            let str_err = err.to_string();
+            if sender.send(Err(err)).await.is_err() {
+                tracing::error!("I have no receiver and I must scream: {str_err}");
+            }
+            return None;
+        }
+    };
+    let _ = events.send(SyncEvent::FileStarted {
+        id,
+        name: name.clone(),
+        path: Some(path.clone()),
+    });
+    let on_progress: ProgressFn = {
+        let events = events.clone();
+        Arc::new(move |bytes| {
+            let _ = events.send(SyncEvent::FileProgress { id, bytes });
+        })
+    };
+    let result = process_file(device, mime.clone(), &source, &name, on_progress)
+        .await
+        .with_context(|| format!("Error uploading to device at {}", device.address()));
+    if let Err(err) = &result {
+        // The pre-upload command runs again when it's retried
+        if requeue(&queued, err, settings, events, tuner) {
+            return Some(queued);
+        }
+    }
+
+    let len = tokio::fs::metadata(&source)
+        .await
+        .map_or(0, |meta| meta.len());
+    let event = if result.is_ok() {
+        SyncEvent::FileDone {
+            id,
+            files: 1,
+            bytes: len,
+        }
+    } else {
+        SyncEvent::FileFailed {
+            id,
+            files: 1,
+            bytes: len,
+        }
+    };
+    let _ = events.send(event);
+    if let Some(tuner) = tuner {
+        if result.is_ok() {
+            tuner.success(len);
+        } else {
+            tuner.failure();
+        }
+    }
+    match result {
+        Ok(outcome) => {
+            settings.hooks.after(&path).await;
+            let uploaded = Uploaded {
+                path,
+                name,
+                sha256: outcome.sha256,
+                mime,
+                bytes: len,
+                confirmed: true,
+            };
+            let _ = sender.send(Ok(uploaded)).await;
+        }
+        Err(err) => {
+            if let Some(error_log) = &settings.error_log {
+                error_log.record(&path, &err);
+            }
+            let err = err.context(path.display().to_string());
+            tracing::debug!("Upload failed: {err:#}");
+            let str_err = err.to_string();
+            if sender.send(Err(err)).await.is_err() {
+                tracing::error!("I have no receiver and I must scream: {str_err}");
+            }
+        }
+    }
+    None
 }
 
+/// Reads the files of a batch and uploads them in one request.
+#[cfg(feature = "batch")]
+async fn send_batch(
+    device: &DeviceClient,
+    files: &[(PathBuf, String, Mime)],
+) -> anyhow::Result<Vec<Uploaded>> {
+    let mut parts = Vec::with_capacity(files.len());
+    for (path, name, mime) in files {
+        tracing::info!("Uploading {}", path.display());
+        let data = tokio::fs::read(path)
+            .await
+            .with_context(|| path.display().to_string())?;
+        parts.push((name, data.len() as u64, mime.clone(), data));
+    }
+    let lens: Vec<_> = parts.iter().map(|(_, len, _, _)| *len).collect();
+
+    let start = Instant::now();
+    let outcomes = device.upload_batch(parts).await?;
+    tracing::debug!(files = files.len(), elapsed = ?start.elapsed(), "Batch upload finished");
+
+    Ok(files
+        .iter()
+        .zip(lens)
+        .zip(outcomes)
+        .map(|(((path, name, mime), bytes), outcome)| Uploaded {
+            path: path.clone(),
+            name: name.clone(),
+            sha256: outcome.sha256,
+            mime: mime.clone(),
+            bytes,
+            confirmed: false,
+        })
+        .collect())
+}
+
+/// Groups files up to [`BATCH_FILE_SIZE`] into batches of `batch_size` for
+/// [`process_all_paths`]. Larger files are uploaded on their own.
+fn batch_files(
+    selected: Vec<(SelectedFile, String)>,
+    batch_size: usize,
+) -> Vec<Vec<(PathBuf, String, Mime)>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    for (file, name) in selected {
+        let small = batch_size > 1 && file.len.is_some_and(|len| len <= BATCH_FILE_SIZE);
+        let file = (file.path, name, file.mime);
+        if !small {
+            batches.push(vec![file]);
+            continue;
+        }
+        batch.push(file);
+        if batch.len() == batch_size {
+            batches.push(std::mem::take(&mut batch));
+        }
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// How each upload is handled, beyond sending the file.
+struct UploadSettings {
+    /// Times to retry a transient failure, from --retries.
+    retries: u8,
+    /// How long to wait before retrying failed uploads.
+    backoff: Backoff,
+    /// Where failed uploads are recorded, from --error-log.
+    error_log: Option<ErrorLog>,
+    /// Commands to run around each upload.
+    hooks: Hooks,
+    /// Types to use instead of guessing from file names.
+    mime_overrides: MimeOverrides,
+}
+
+/// Runs the pre-upload command for a file, returning the file to upload with
+/// its name and type. A file the command swaps in keeps the upload name, but
+/// with its own extension.
+async fn pre_upload(
+    device: &DeviceClient,
+    settings: &UploadSettings,
+    path: &Path,
+    name: String,
+    mime: Mime,
+) -> anyhow::Result<(PathBuf, String, Mime)> {
+    let Some(source) = settings.hooks.before(path).await? else {
+        return Ok((path.to_path_buf(), name, mime));
+    };
+    let guess = settings.mime_overrides.guess(&source);
+    let Some(mime) = guess.into_iter().find(|mime| device.mime_supported(mime)) else {
+        bail!("{} isn't a type the device supports", source.display());
+    };
+    let name = match source.extension() {
+        Some(ext) => Path::new(&name)
+            .with_extension(ext)
+            .to_string_lossy()
+            .into_owned(),
+        None => name,
+    };
+    Ok((source, name, mime))
+}
+
+/// Limits on how many uploads run at once, made once for the whole run so
+/// every device shares the --global-tasks permits.
+#[derive(Clone)]
+struct TaskLimits {
+    /// Uploads to a single device, from --tasks.
+    per_device: usize,
+    /// Uploads across all devices, from --global-tasks. Every device's uploads
+    /// share these permits.
+    global: Arc<Semaphore>,
+    /// Whether to tune the uploads per device, up to `per_device`, for
+    /// --auto-tasks.
+    auto: bool,
+}
+
+impl TaskLimits {
+    fn new(per_device: u8, global: Option<u8>, auto: bool) -> Self {
+        let global = global.map_or(Semaphore::MAX_PERMITS, usize::from);
+        Self {
+            per_device: per_device.into(),
+            global: Arc::new(Semaphore::new(global)),
+            auto,
+        }
+    }
+}
+
+/// Uploads the batches of selected files under their upload names, sending
+/// each file uploaded, or the error for one that failed. How the uploads are
+/// going is sent to `events`, with each batch's index as its [`UploadId`].
+/// Batches of a single file report their progress as they're sent.
+///
+/// Uploads that fail in a way worth retrying are tried again after all the
+/// others have been, rather than holding up a task while they wait, up to
+/// --retries times.
 async fn process_all_paths(
     device: Arc<DeviceClient>,
-    selected: Vec<(PathBuf, Mime)>,
-    sender: mpsc::Sender<anyhow::Error>,
-    max_tasks: usize,
-    progress: Progression,
+    batches: Vec<Vec<(PathBuf, String, Mime)>>,
+    sender: mpsc::Sender<anyhow::Result<Uploaded>>,
+    limits: TaskLimits,
+    settings: Arc<UploadSettings>,
+    events: EventSender,
 ) {
-    let semaphore = Arc::new(Semaphore::new(max_tasks));
-
-    let mut tasks = Vec::new();
-    for (path, mime) in selected {
-        let progress = progress.clone();
-        let sender = sender.clone();
-        let device = device.clone();
-        let permit = semaphore.clone().acquire_owned().await.unwrap();
-        let task = tokio::spawn(async move {
-            if let Err(err) = process_file(&device, mime, &path, permit)
-                .await
-                .with_context(|| format!("{}", path.display()))
-            {
-                //
-                let str_err = err.to_string();
-                if sender.send(err).await.is_err() {
-                    tracing::error!("I have no receiver and I must scream: {str_err}");
+    let max_tasks = limits.per_device;
+    let tuner = limits.auto.then(|| Arc::new(AutoTasks::new(max_tasks)));
+    let semaphore = match &tuner {
+        Some(tuner) => tuner.semaphore(),
+        None => Arc::new(Semaphore::new(max_tasks)),
+    };
+
+    let mut queue: Vec<_> = batches
+        .into_iter()
+        .enumerate()
+        .map(|(id, files)| Queued {
+            id,
+            files,
+            attempt: 0,
+        })
+        .collect();
+    let mut pass = 0;
+    while !queue.is_empty() {
+        if pass > 0 {
+            tracing::info!("Retrying {} failed uploads", queue.len());
+            settings.backoff.wait(pass).await;
+        }
+        let mut tasks = Vec::new();
+        for (idx, queued) in queue.drain(..).enumerate() {
+            let events = events.clone();
+            let sender = sender.clone();
+            let device = device.clone();
+            let settings = settings.clone();
+            let tuner = tuner.clone();
+            // Wait on this device first, so its queued uploads don't hold
+            // global permits other devices could use
+            let device_permit = semaphore.clone().acquire_owned().await.unwrap();
+            let global_permit = limits.global.clone().acquire_owned().await.unwrap();
+            // With a single task, the previous upload is done once we have the
+            // permit, so check the device is still around before the next one
+            if max_tasks == 1 && (idx > 0 || pass > 0) {
+                if let Err(err) = device.ping().await {
+                    let _ = sender.send(Err(err.into())).await;
+                    return;
                 }
             }
-            progress.inc(1);
-        });
-        tasks.push(task);
-    }
-}
-
-/// Recursively get all file paths in a directory.
-fn get_dir_paths(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
-    tracing::trace!("reading dir {}", dir.display());
-    let mut paths = Vec::new();
-    if dir.is_dir() {
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry.with_context(|| format!("while recursing {}", dir.display()))?;
-            let path = entry.path();
-            if path.is_dir() {
-                paths.append(&mut get_dir_paths(&path)?);
-            } else {
-                paths.push(path);
+            let span = match queued.files.as_slice() {
+                [(path, _, _)] => tracing::info_span!("upload", path = %path.display()),
+                files => tracing::info_span!("upload", batch = files.len()),
+            };
+            let task = tokio::spawn(
+                async move {
+                    let _global_permit = global_permit;
+                    let requeued = match queued.files.len() {
+                        #[cfg(feature = "batch")]
+                        2.. => {
+                            process_batch(
+                                &device,
+                                queued,
+                                &sender,
+                                &settings,
+                                &events,
+                                tuner.as_deref(),
+                            )
+                            .await
+                        }
+                        _ => {
+                            process_single(
+                                &device,
+                                queued,
+                                &sender,
+                                &settings,
+                                &events,
+                                tuner.as_deref(),
+                            )
+                            .await
+                        }
+                    };
+                    match &tuner {
+                        Some(tuner) => tuner.release(device_permit),
+                        None => drop(device_permit),
+                    }
+                    requeued.map(|mut queued| {
+                        queued.attempt += 1;
+                        queued
+                    })
+                }
+                .instrument(span),
+            );
+            tasks.push(task);
+        }
+        for task in tasks {
+            match task.await {
+                Ok(Some(queued)) => queue.push(queued),
+                Ok(None) => {}
+                Err(err) => tracing::error!("Upload task failed: {err}"),
             }
         }
+        pass += 1;
     }
-
-    Ok(paths)
 }
 
-async fn app_main(args: Args) -> anyhow::Result<()> {
-    let mut api = doppler_ws::TransferClient::connect()
+/// Removes files whose contents were already uploaded to the device, or that
+/// duplicate an earlier file in the selection, for --dedup-content.
+async fn dedup_content(
+    library: &Library,
+    device_id: &str,
+    selected: Vec<SelectedFile>,
+    progress: ProgressMode,
+) -> anyhow::Result<Vec<SelectedFile>> {
+    let mut seen = library
+        .uploaded_hashes(device_id)
         .await
-        .context("Error accessing Doppler API")?;
-    let library = Library::open().await?;
+        .context("Couldn't read previous uploads")?;
 
-    // First, process the short-circuit stuff
-    if args.list_devices {
-        let names = library.device_names().await?;
-        println!("Saved devices:");
-        for name in names {
-            println!("  {name}");
+    let spin = Progression::new_spinner(progress, "Hashing files");
+    spin.set_length(selected.len() as u64);
+    spin.enable_steady_tick(Duration::from_millis(300));
+    let hashed: Vec<_> = tokio::task::block_in_place(|| {
+        selected
+            .into_par_iter()
+            .map(|file| {
+                let hash = select::file_sha256(&file.path);
+                spin.inc(1);
+                (file, hash)
+            })
+            .collect()
+    });
+    spin.finish_and_clear();
+
+    let mut unique = Vec::new();
+    for (file, hash) in hashed {
+        match hash {
+            Ok(hash) => {
+                if seen.insert(hash) {
+                    unique.push(file);
+                } else {
+                    tracing::info!(
+                        "{}: same contents already uploaded, skipping",
+                        file.path.display()
+                    );
+                }
+            }
+            // Leave it to the upload to report why the file can't be read
+            Err(err) => {
+                tracing::debug!("{}: couldn't hash: {err}", file.path.display());
+                unique.push(file);
+            }
         }
-        std::process::exit(0);
-    } else if let Some(name) = args.drop_device {
-        library.delete_device(&name).await?;
-        println!("Device {name} forgotten.");
-        std::process::exit(0);
     }
+    Ok(unique)
+}
+
+/// Checks whether a file whose name is taken by an earlier upload is the
+/// same file, so --on-conflict rename doesn't upload it again under a new
+/// name. It's the same if its contents match the upload recorded under
+/// `name`, or one it was renamed to before. Uploads recorded without a hash
+/// can't be compared, so are taken to be the same.
+fn same_upload(path: &Path, name: &str, uploaded: &HashMap<String, Option<String>>) -> bool {
+    let Some(Some(_)) = uploaded.get(name) else {
+        return true;
+    };
+    match tokio::task::block_in_place(|| select::file_sha256(path)) {
+        Ok(hash) => uploaded.values().flatten().any(|sha256| *sha256 == hash),
+        // Leave it to the upload to report why the file can't be read
+        Err(_) => false,
+    }
+}
+
+/// Gets the name a file is uploaded to the device with, from `template` if
+/// given.
+fn upload_name(path: &Path, template: Option<&NameTemplate>) -> String {
+    match template {
+        Some(template) => template.render(path),
+        None => path.file_name().map_or_else(
+            || path.display().to_string(),
+            |n| n.to_string_lossy().into_owned(),
+        ),
+    }
+}
+
+/// Picks the name each selected file is uploaded under. Files with the same
+/// name as an earlier one in the selection, or one in `taken`, are uploaded
+/// anyway, skipped or renamed per `on_conflict`.
+fn upload_names(
+    selected: Vec<SelectedFile>,
+    template: Option<&NameTemplate>,
+    on_conflict: OnConflict,
+    mut taken: HashSet<String>,
+) -> Vec<(SelectedFile, String)> {
+    let mut named = Vec::with_capacity(selected.len());
+    for file in selected {
+        let path = &file.path;
+        let mut name = upload_name(path, template);
+        if taken.contains(&name) {
+            match on_conflict {
+                OnConflict::Overwrite => tracing::warn!(
+                    "{}: another file is also uploaded as {name}",
+                    path.display()
+                ),
+                OnConflict::Skip => {
+                    tracing::info!(
+                        "{}: another file is also uploaded as {name}, skipping",
+                        path.display()
+                    );
+                    continue;
+                }
+                OnConflict::Rename => {
+                    let mut n = 2;
+                    let mut renamed = numbered_name(&name, n);
+                    while taken.contains(&renamed) {
+                        n += 1;
+                        renamed = numbered_name(&name, n);
+                    }
+                    tracing::info!(
+                        "{}: another file is also uploaded as {name}, uploading as {renamed}",
+                        path.display()
+                    );
+                    name = renamed;
+                }
+            }
+        }
+        taken.insert(name.clone());
+        named.push((file, name));
+    }
+    named
+}
+
+/// Adds `n` to a file name before its extension, such as "track01 (2).mp3".
+fn numbered_name(name: &str, n: u32) -> String {
+    let path = Path::new(name);
+    let stem = path
+        .file_stem()
+        .map_or_else(|| name.into(), |stem| stem.to_string_lossy());
+    match path.extension() {
+        Some(ext) => format!("{stem} ({n}).{}", ext.to_string_lossy()),
+        None => format!("{stem} ({n})"),
+    }
+}
+
+/// Prints everything the device reports about itself, for --probe.
+fn print_probe(device: &DeviceClient, output: OutputFormat) -> anyhow::Result<()> {
+    match output {
+        OutputFormat::Text => {
+            println!("Device: {}", device.device_name());
+            println!(
+                "App: {} (version {})",
+                device.app_name(),
+                device.app_version()
+            );
+            println!("Supported MIME types:");
+            for mime in device.supported_mimetypes() {
+                println!("  {mime}");
+            }
+            println!("Known file extensions:");
+            for ext in device.supported_extensions() {
+                println!("  {ext}");
+            }
+        }
+        OutputFormat::Json => {
+            let info = serde_json::json!({
+                "device_name": device.device_name(),
+                "app_name": device.app_name(),
+                "app_version": device.app_version(),
+                "supported_mimetypes": device.supported_mimetypes(),
+                "known_file_extensions": device.supported_extensions(),
+                // Everything the device sent, for spotting new fields
+                "raw_info": device.info_raw(),
+            });
+            println!("{}", serde_json::to_string_pretty(&info)?);
+        }
+    }
+    Ok(())
+}
+
+/// Prints how many files of each type were uploaded to a device, given as its
+/// ID and name, once the sync is done, and how fast.
+fn print_summary(
+    (id, name): (&str, &str),
+    counts: &BTreeMap<String, TypeCount>,
+    elapsed: Duration,
+    output: OutputFormat,
+) {
+    let files: usize = counts.values().map(|count| count.files).sum();
+    let bytes: u64 = counts.values().map(|count| count.bytes).sum();
+    let bytes_per_sec = (bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON)) as u64;
+    match output {
+        OutputFormat::Text if files == 0 => println!("No files were uploaded to {name}"),
+        OutputFormat::Text => {
+            let by_mime: Vec<_> = counts
+                .iter()
+                .map(|(mime, count)| format!("{} {mime}", count.files))
+                .collect();
+            println!(
+                "Uploaded {files} files ({}) to {name} in {} at {}/s: {}",
+                HumanBytes(bytes),
+                HumanDuration(elapsed),
+                HumanBytes(bytes_per_sec),
+                by_mime.join(", ")
+            );
+        }
+        OutputFormat::Json => {
+            let event = serde_json::json!({
+                "event": "uploaded",
+                "device": id,
+                "files": files,
+                "bytes": bytes,
+                "elapsed_secs": elapsed.as_secs_f64(),
+                "bytes_per_sec": bytes_per_sec,
+                "by_mime": by_mime_json(counts),
+            });
+            println!("{event}");
+        }
+    }
+}
+
+/// Converts counts by type to a JSON map from each type to its number of
+/// files and bytes.
+fn by_mime_json(
+    counts: &BTreeMap<String, TypeCount>,
+) -> serde_json::Map<String, serde_json::Value> {
+    counts
+        .iter()
+        .map(|(mime, count)| {
+            let value = serde_json::json!({ "files": count.files, "bytes": count.bytes });
+            (mime.clone(), value)
+        })
+        .collect()
+}
+
+/// Prints the number and size of the selected files by type, for
+/// --count-only.
+fn print_counts(counts: &BTreeMap<String, TypeCount>, output: OutputFormat) -> anyhow::Result<()> {
+    let files: usize = counts.values().map(|count| count.files).sum();
+    let bytes: u64 = counts.values().map(|count| count.bytes).sum();
+    match output {
+        OutputFormat::Text => {
+            let width = counts.keys().map(String::len).max().unwrap_or(0).max(5);
+            println!("{:<width$}  {:>7}  {:>11}", "Type", "Files", "Size");
+            for (mime, count) in counts {
+                let size = HumanBytes(count.bytes).to_string();
+                println!("{mime:<width$}  {:>7}  {size:>11}", count.files);
+            }
+            let size = HumanBytes(bytes).to_string();
+            println!("{:<width$}  {files:>7}  {size:>11}", "Total");
+        }
+        OutputFormat::Json => {
+            let summary = serde_json::json!({
+                "files": files,
+                "bytes": bytes,
+                "by_mime": by_mime_json(counts),
+            });
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether to resend the push notification to a device that hasn't
+/// responded. Without a terminal to ask on, it's never resent, so an
+/// unattended sync fails rather than waiting forever.
+fn ask_resend(device_name: &str) -> anyhow::Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    eprint!("{device_name} hasn't responded. Send the notification again? [Y/n] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(answer.is_empty() || answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Asks which saved device to sync to, for --interactive. Returns `None` to
+/// pair a new device with a code, which is also the choice when there's no
+/// terminal to ask in or nothing saved to pick from.
+fn pick_device(devices: &[DeviceRecord]) -> anyhow::Result<Option<String>> {
+    if devices.is_empty() || !std::io::stdin().is_terminal() || !std::io::stderr().is_terminal() {
+        return Ok(None);
+    }
+    let mut items: Vec<_> = devices
+        .iter()
+        .map(|device| match device.last_synced {
+            Some(ts) => format!("{} (last synced {})", device.name, format_age(ts)),
+            None => format!("{} (never synced)", device.name),
+        })
+        .collect();
+    items.push("Pair a new device with a code".to_string());
+
+    let choice = dialoguer::Select::new()
+        .with_prompt("Sync to")
+        .items(&items)
+        .default(0)
+        .interact_opt()?;
+    match choice {
+        Some(index) => Ok(devices.get(index).map(|device| device.name.clone())),
+        None => bail!("No device chosen"),
+    }
+}
+
+/// Gets the HTTP version to use with the device from the arguments.
+fn http_version(args: &Args) -> HttpVersion {
+    if args.http2 {
+        HttpVersion::Auto
+    } else {
+        HttpVersion::Http1
+    }
+}
+
+/// Tries to reach a saved device at the LAN URL it was last paired at,
+/// without going through the pairing API.
+///
+/// The address may have been handed to another device since. The device's
+/// info doesn't include its ID, so the device found there is checked against
+/// the name recorded for the saved device's ID at its last sync, rather than
+/// the saved name, which can be changed here. Devices not synced to yet are
+/// always paired through the API.
+async fn connect_direct(library: &Library, args: &Args, device: &Device) -> Option<DeviceClient> {
+    let id = device.id.as_ref()?;
+    let lan_url = library.lan_url(id).await.ok().flatten()?;
+    let reported_name = library.reported_name(id).await.ok().flatten()?;
+    tracing::debug!("Trying last known LAN URL {lan_url}");
+    // Only reaching the device is limited, not the requests made once it's
+    // reached
+    let client = DeviceClientBuilder::new(&lan_url)
+        .push_token(device.clone())
+        .http_version(http_version(args))
+        .user_agent(HeaderValue::from_static(USER_AGENT))
+        .accept_invalid_certs(args.accept_invalid_certs)
+        .connect();
+    let client = match tokio::time::timeout(DIRECT_CONNECT_TIMEOUT, client).await {
+        Ok(Ok(client)) => client,
+        Ok(Err(err)) => {
+            tracing::debug!("{lan_url}: {err}, pairing through the API instead");
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!("{lan_url} timed out, pairing through the API instead");
+            return None;
+        }
+    };
+    if client.device_name() != reported_name {
+        tracing::debug!(
+            "{lan_url} is now {}, pairing through the API instead",
+            client.device_name()
+        );
+        return None;
+    }
+    Some(client)
+}
+
+/// Pairs through the pairing API, either by sending a push notification to
+/// the `saved` device or by showing the pairing code. Returns the device's
+/// ID, its saved record if any, and the connection to it.
+async fn pair(
+    library: &Library,
+    args: &Args,
+    saved: Option<&Device>,
+) -> anyhow::Result<(String, Option<Device>, PairedDevice)> {
+    let mut api = open_api(library, args).await?;
 
-    let mut response = if let Some(device) = args.device {
+    let response = if let Some(device) = saved {
         // Perform the saved device pairing flow
-        let Some(device) = library.get_device(&device).await? else {
-            bail!("Device name not found");
-        };
+        let device_name = device.display_name();
         let spin = Progression::new_spinner(
             args.progress,
-            format!(
-                "Waiting for {} to respond...",
-                device.name.as_deref().unwrap_or("device")
-            ),
+            format!("Waiting for {device_name} to respond..."),
         );
         spin.enable_steady_tick(Duration::from_millis(300));
-        let result = api.get_saved_device(&device).await;
+        let result = async {
+            api.resend_push(device).await?;
+            loop {
+                match api
+                    .wait_for_saved_device(device, Some(PUSH_RESEND_AFTER))
+                    .await
+                {
+                    Err(ApiError::Timeout(_)) => {
+                        if !spin.suspend(|| ask_resend(device_name))? {
+                            bail!("{device_name} didn't respond");
+                        }
+                        tracing::info!("Resending notification to {device_name}");
+                        api.resend_push(device).await?;
+                    }
+                    result => return Ok(result?),
+                }
+            }
+        }
+        .await;
         spin.finish_and_clear();
         result
     } else {
         // Pair by code
         let pairing_code = api.code();
         if !args.no_qr {
-            let qrcode =
-                qrencode::QrCode::new(pairing_code).context("Failed to generate QR code")?;
-            let encoded = qrcode.render::<char>().module_dimensions(2, 1).build();
-            println!("{encoded}");
+            match render_qr(pairing_code)? {
+                Some(encoded) => println!("{encoded}"),
+                None => println!("The terminal is too narrow for the QR code."),
+            }
         }
 
         println!("Use code {pairing_code} to connect your device.");
 
-        api.get_new_device().await
+        api.get_new_device().await.map_err(anyhow::Error::from)
     }
     .context("Failed to pair")?;
+    tracing::debug!("Pairing with device {}", response.short_id());
 
     // Check if we've previously saved the device
-    let is_saved = matches!(library.get_device_by_id(response.id()).await, Ok(Some(_)));
+    let saved_device = library.get_device_by_id(response.id()).await.ok().flatten();
+    let is_saved = saved_device.is_some();
 
-    let device = api
-        .confirm_device(&mut response, is_saved)
+    let paired = api
+        .confirm(&response, is_saved)
         .await
         .context("Couldn't get device URL")?;
+    // Pairing is done, so let the API end the session
+    if let Err(err) = api.close().await {
+        tracing::debug!("Error closing pairing connection: {err}");
+    }
 
-    // If the device reports a push token, that means the device requested to be saved
-    if let Some(push_token) = device.push_token() {
-        if !is_saved {
-            tracing::info!("Saving device per its request");
-            library
-                .add_device(push_token)
-                .await
-                .context("Couldn't save device to database")?;
+    Ok((response.id().to_string(), saved_device, paired))
+}
+
+/// Gets how long to wait between retries, from --retry-base and --retry-max.
+fn backoff(args: &Args) -> Backoff {
+    Backoff {
+        base: args.retry_base,
+        max: args.retry_max,
+        jitter: RETRY_JITTER,
+        ..Backoff::default()
+    }
+}
+
+/// Connects to the pairing API as this machine.
+async fn open_api(library: &Library, args: &Args) -> anyhow::Result<TransferClient> {
+    let client_id = library.client_id().await?;
+    TransferClientBuilder::default()
+        .client_id(client_id)
+        .connect_retries(CONNECT_RETRIES)
+        .connect_backoff(backoff(args))
+        .http_version(http_version(args))
+        .user_agent(HeaderValue::from_static(USER_AGENT))
+        .accept_invalid_device_certs(args.accept_invalid_certs)
+        .connect()
+        .await
+        .context("Error accessing Doppler API")
+}
+
+/// Renders the pairing QR code as text that fits the terminal, or `None` if
+/// it can't fit without wrapping, which would make it unscannable.
+///
+/// Each module is drawn two characters wide where there's room, which looks
+/// closest to square, and otherwise with half blocks a single character wide.
+/// Output that isn't to a terminal gets the wide version.
+fn render_qr(code: &str) -> anyhow::Result<Option<String>> {
+    let qrcode = qrencode::QrCode::new(code).context("Failed to generate QR code")?;
+    // Including the quiet zone on either side
+    let modules = qrcode.width() + 8;
+    let columns = terminal_size::terminal_size().map(|(Width(width), _)| usize::from(width));
+    Ok(match columns {
+        Some(columns) if columns < modules => None,
+        Some(columns) if columns < modules * 2 => {
+            Some(qrcode.render::<unicode::Dense1x2>().build())
         }
+        _ => Some(qrcode.render::<char>().module_dimensions(2, 1).build()),
+    })
+}
+
+/// Describes how long ago a Unix timestamp was, such as "3 days ago".
+fn format_age(timestamp: i64) -> String {
+    let secs = db::unix_now().saturating_sub(timestamp).max(0);
+    let (count, unit) = match secs {
+        0..=59 => return "just now".to_string(),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86_399 => (secs / 3600, "hour"),
+        _ => (secs / 86_400, "day"),
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+/// Checks whether a failed upload is worth retrying.
+fn is_retryable(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<ApiError>()
+        .is_some_and(ApiError::is_retryable)
+}
+
+async fn app_main(mut args: Args) -> anyhow::Result<()> {
+    if args.selftest {
+        return selftest::run(args.output).await;
+    }
+    if let Some(manifest) = &args.from_file {
+        let paths = read_manifest(manifest)?;
+        args.paths.extend(paths);
     }
 
-    // Get all paths we care about
-    let mut selected = Vec::new();
-    for path in args.paths {
-        if path.is_dir() {
-            let spin = Progression::new_spinner(
-                args.progress,
-                format!("Finding music files for {}", path.display()),
-            );
-            spin.enable_steady_tick(Duration::from_millis(300));
-            if args.recurse {
-                let dir = path.clone();
-                // Recursively get all paths, then find the ones with MIME types we care about
-                let mut paths = tokio::task::spawn_blocking(move || get_dir_paths(&dir))
-                    .await
-                    .with_context(|| format!("while recursing {}", path.display()))??
-                    .into_iter()
-                    .filter_map(|p| {
-                        mime_guess::from_path(&p)
-                            .iter()
-                            .find(|m| device.mime_supported(m))
-                            .map(|mime| (p, mime))
-                    })
-                    .collect();
-                selected.append(&mut paths);
-            } else {
-                tracing::warn!(
-                    "skipping directory '{}' as -r was not defined",
-                    path.display()
-                );
+    let filters = Filters {
+        max_file_size: args.max_file_size,
+        modified_since: args.modified_since,
+        strict_mime: args.strict_mime,
+        mime_overrides: MimeOverrides::new(args.force_mime.clone(), &args.mime_map),
+        require_tags: args.require_tags,
+        strict: args.strict,
+    };
+    if args.count_only {
+        let (events, shown) = progress::spawn(args.progress, args.progress_style);
+        let selection =
+            Selection::from_args(&CommonTypes, args.paths, args.recurse, &filters, &events).await;
+        drop(events);
+        let _ = shown.await;
+        let selection = selection?;
+        print_counts(&selection.count_by_mime(), args.output)?;
+        std::process::exit(0);
+    }
+
+    let library = Library::open().await?;
+
+    // First, process the short-circuit stuff
+    if args.list_devices {
+        let devices = library.devices().await?;
+        println!("Saved devices:");
+        for device in devices {
+            let mut details = vec![match device.last_synced {
+                Some(ts) => format!("last synced {}", format_age(ts)),
+                None => "never synced".to_string(),
+            }];
+            if let Some(ts) = device.created_at {
+                details.push(format!("saved {}", format_age(ts)));
+            }
+            if let Some(version) = device.app_version {
+                details.push(format!("app version {version}"));
+            }
+            if let Some(reported) = device.device_name.filter(|n| *n != device.name) {
+                details.push(format!("reported as {reported}"));
+            }
+            println!("  {} ({})", device.name, details.join(", "));
+        }
+        std::process::exit(0);
+    } else if let Some(name) = args.drop_device {
+        library.delete_device(&name).await?;
+        println!("Device {name} forgotten.");
+        std::process::exit(0);
+    } else if args.prune_devices {
+        prune::run(&library, &args).await?;
+        std::process::exit(0);
+    }
+
+    let mut targets = Vec::new();
+    for name in &args.device {
+        match library.get_device(name).await? {
+            Some(device) => targets.push(Some(device)),
+            None => bail!("Device {name} not found"),
+        }
+    }
+    let mut routes = Routes::new(targets.iter().flatten().filter_map(|d| d.id.clone()));
+    for (ext, name) in &args.route {
+        let Some(device) = library.get_device(name).await? else {
+            bail!("Device {name} not found");
+        };
+        let Some(id) = device.id.clone() else {
+            bail!("Device {name} has no ID, so files can't be routed to it");
+        };
+        if !targets.iter().flatten().any(|d| d.id == device.id) {
+            targets.push(Some(device));
+        }
+        routes.add(ext.clone(), id);
+    }
+    if targets.is_empty() && args.interactive {
+        let devices = library.devices().await?;
+        if let Some(name) = pick_device(&devices)? {
+            match library.get_device(&name).await? {
+                Some(device) => targets.push(Some(device)),
+                None => bail!("Device {name} not found"),
+            }
+        }
+    }
+    if targets.is_empty() {
+        // Pair a new device by code
+        targets.push(None);
+    }
+
+    // Pair with every device before uploading, one at a time: each device is
+    // sent its notification only once the one before has answered or failed,
+    // so the user can walk away once the last one is paired
+    let target_count = targets.len();
+    let mut devices = Vec::new();
+    for saved in targets {
+        let label = saved
+            .as_ref()
+            .map_or(Device::UNNAMED, Device::display_name)
+            .to_string();
+        match connect(&library, &args, saved).await {
+            Ok(device) => devices.push(device),
+            Err(err) if target_count > 1 && !args.all_required => {
+                tracing::warn!("Skipping {label}: {err:#}");
             }
-            spin.finish_and_clear();
+            Err(err) => return Err(err),
+        }
+    }
+    if devices.is_empty() {
+        bail!("None of the devices could be paired");
+    }
+
+    let limits = TaskLimits::new(args.tasks, args.global_tasks, args.auto_tasks);
+    if let Some(dir) = &args.watch {
+        let Ok([device]) = <[_; 1]>::try_from(devices) else {
+            bail!("--watch uploads to a single device");
+        };
+        return watch::run(&library, &args, &filters, &limits, dir, device).await;
+    }
+    for device in devices {
+        if args.probe {
+            print_probe(&device.client, args.output)?;
         } else {
-            let Some(mime) = mime_guess::from_path(&path)
-                .iter()
-                .find(|m| device.mime_supported(m))
-            else {
-                bail!("{}: unsupported mime type", path.display());
+            sync_device(&library, &args, &filters, &routes, &limits, device).await?;
+        }
+    }
+    Ok(())
+}
+
+/// A device that's been paired and is ready to sync.
+struct Connected {
+    id: String,
+    /// The saved name of the device, or the name it reports if it isn't saved.
+    name: String,
+    client: DeviceClient,
+    /// Whether the device asked to be saved and isn't yet.
+    save: bool,
+}
+
+/// Connects to a device, directly if it's `saved` and still reachable at its
+/// last known address, or through the pairing API otherwise.
+async fn connect(
+    library: &Library,
+    args: &Args,
+    saved: Option<Device>,
+) -> anyhow::Result<Connected> {
+    // A saved device may still be reachable where it was last time, which
+    // skips the pairing API and push notification entirely
+    let direct = match &saved {
+        Some(device) => connect_direct(library, args, device).await,
+        None => None,
+    };
+
+    let (device_id, saved_device, paired) = match (saved, direct) {
+        (Some(device), Some(client)) => {
+            let id = device.id.clone().unwrap_or_default();
+            let paired = PairedDevice {
+                client,
+                save_requested: false,
             };
+            (id, Some(device), paired)
+        }
+        (saved, _) => pair(library, args, saved.as_ref()).await?,
+    };
+    set_up_device(library, args, device_id, saved_device, paired).await
+}
+
+/// Pairs with the device with ID `id` again through the pairing API, after
+/// the device turned the last session away. Returns a connection with a new
+/// session.
+async fn pair_again(library: &Library, args: &Args, id: &str) -> anyhow::Result<DeviceClient> {
+    let saved = library.get_device_by_id(id).await?;
+    let (device_id, saved_device, paired) = pair(library, args, saved.as_ref()).await?;
+    if device_id != id {
+        bail!("Paired with a different device ({device_id}) than the one being synced to");
+    }
+    let connected = set_up_device(library, args, device_id, saved_device, paired).await?;
+    save_device(library, &connected).await?;
+    Ok(connected.client)
+}
 
-            selected.push((path, mime));
+/// Applies the upload options to a newly paired device, and notes where it
+/// was reached.
+async fn set_up_device(
+    library: &Library,
+    args: &Args,
+    device_id: String,
+    saved_device: Option<Device>,
+    paired: PairedDevice,
+) -> anyhow::Result<Connected> {
+    let is_saved = saved_device.is_some();
+    let mut device = paired.client;
+    device.set_verify_checksum(args.verify);
+    device.set_name_normalization(args.normalize_names.into());
+    device.set_progress_throttle(ProgressThrottle {
+        interval: PROGRESS_INTERVAL,
+        ..ProgressThrottle::default()
+    });
+    #[cfg(feature = "playlists")]
+    device.set_playlist(args.playlist.clone());
+    if !device.app_version_tested() {
+        tracing::warn!(
+            "Doppler app version {} hasn't been tested with radarsync, as no tested versions \
+             are known yet; uploads may not work as expected",
+            device.app_version()
+        );
+    }
+
+    // The device may have been renamed on the phone since it was saved
+    let mut saved_device = saved_device;
+    if let Some(saved) = &mut saved_device {
+        let current = device.device_name();
+        if let Some(old) = saved.name.as_deref().filter(|&old| old != current) {
+            match library.rename_device(&device_id, current).await {
+                Ok(()) => {
+                    tracing::warn!("Device renamed from {old} to {current}");
+                    saved.name = Some(current.to_string());
+                }
+                Err(err) => tracing::warn!(
+                    "Device renamed from {old} to {current}, but couldn't update the saved \
+                     name: {err:#}"
+                ),
+            }
+        }
+    }
+
+    let name = saved_device
+        .and_then(|d| d.name)
+        .unwrap_or_else(|| device.device_name().to_string());
+    tracing::info!(
+        id = &device_id,
+        lan_url = device.lan_url(),
+        "Paired with {name}"
+    );
+    if args.output == OutputFormat::Json {
+        let event = serde_json::json!({
+            "event": "paired",
+            "id": &device_id,
+            "name": name,
+            "lan_url": device.lan_url(),
+        });
+        println!("{event}");
+    }
+
+    Ok(Connected {
+        id: device_id,
+        name,
+        client: device,
+        save: paired.save_requested && !is_saved,
+    })
+}
+
+/// Uploads the files given on the command line to a paired device.
+async fn sync_device(
+    library: &Library,
+    args: &Args,
+    filters: &Filters,
+    routes: &Routes,
+    limits: &TaskLimits,
+    connected: Connected,
+) -> anyhow::Result<()> {
+    save_device(library, &connected).await?;
+    let Connected {
+        id: device_id,
+        name: device_name,
+        client: device,
+        ..
+    } = connected;
+
+    let started = SystemTime::now();
+    let filters = if args.since_last_sync {
+        since_last_sync(library, &device_id, filters).await
+    } else {
+        filters.clone()
+    };
+    let (events, shown) = progress::spawn(args.progress, args.progress_style);
+    let selection =
+        Selection::from_args(&device, args.paths.clone(), args.recurse, &filters, &events).await;
+    drop(events);
+    let _ = shown.await;
+    let mut selection = selection?;
+    let found = selection.files.len();
+    selection.files.retain(|file| {
+        let accepted = routes.accepts(&file.path, &device_id);
+        if !accepted {
+            tracing::debug!("{}: routed to another device", file.path.display());
+        }
+        accepted
+    });
+    if found > 0 && selection.files.is_empty() {
+        println!("No files are routed to {device_name}.");
+        return Ok(());
+    }
+    let Selection {
+        files: mut selected,
+        skipped,
+        outcomes,
+    } = selection;
+
+    if !skipped.is_empty() {
+        tracing::info!("skipped {} files", skipped.len());
+        for (path, reason) in &skipped {
+            tracing::debug!("  {}: {reason}", path.display());
         }
     }
 
+    let all_unchanged = !skipped.is_empty()
+        && skipped
+            .iter()
+            .all(|(_, reason)| matches!(reason, SkipReason::NotModified));
+    if selected.is_empty() && args.since_last_sync && all_unchanged {
+        println!("No files changed since the last sync to {device_name}.");
+        return Ok(());
+    }
     if selected.is_empty() {
-        bail!("No music files were found");
+        let reasons = outcomes
+            .iter()
+            .map(|(path, outcome)| format!("{}: {outcome}", path.display()))
+            .collect::<Vec<_>>()
+            .join("; ");
+        bail!("No music files were found ({reasons})");
+    }
+    for (path, outcome) in &outcomes {
+        if matches!(outcome, PathOutcome::Selected(_)) {
+            tracing::debug!("{}: {outcome}", path.display());
+        } else {
+            tracing::warn!("{}: {outcome}", path.display());
+        }
+    }
+
+    // Catch files that were uploaded on an earlier run, which the device
+    // would otherwise add again
+    let uploaded = library
+        .uploaded_files(&device_id)
+        .await
+        .unwrap_or_else(|err| {
+            tracing::warn!("Couldn't read previous uploads: {err:#}");
+            Default::default()
+        });
+    selected.retain(|SelectedFile { path, .. }| {
+        let name = upload_name(path, args.name_template.as_ref());
+        if !uploaded.contains_key(&name) {
+            true
+        } else if args.skip_existing
+            || args.on_conflict == OnConflict::Skip
+            || (args.on_conflict == OnConflict::Rename && same_upload(path, &name, &uploaded))
+        {
+            tracing::info!("{}: already uploaded, skipping", path.display());
+            false
+        } else if args.on_conflict == OnConflict::Rename {
+            // A different file, given another name along with the rest of
+            // the upload names
+            true
+        } else {
+            tracing::warn!(
+                "{}: a file named {name} was already uploaded to this device and may be \
+                 duplicated (use --skip-existing or --on-conflict to avoid it)",
+                path.display()
+            );
+            true
+        }
+    });
+    if args.dedup_content {
+        selected = dedup_content(library, &device_id, selected, args.progress).await?;
+    }
+    if selected.is_empty() {
+        println!("All files were already uploaded to {device_name}.");
+        return Ok(());
     }
 
-    let file_count = selected.len();
     tracing::info!("Uploading {} files", selected.len());
 
-    let device = Arc::new(device);
-    let (send, mut recv) = mpsc::channel::<anyhow::Error>(1);
+    device
+        .ping()
+        .await
+        .context("Device stopped responding before uploading")?;
 
-    let progress = Progression::new(
-        args.progress,
-        file_count as u64,
-        format!("Uploading {file_count} files"),
+    let mut device = Arc::new(device);
+    let mut uploads = Uploads::default();
+    let mut paired_again = false;
+    let upload_start = Instant::now();
+    loop {
+        let sent_before = uploads.paths.len();
+        let result = upload_files(
+            library,
+            args,
+            &device,
+            &device_id,
+            selected.clone(),
+            limits,
+            &mut uploads,
+        )
+        .await;
+        let Err(err) = result else {
+            break;
+        };
+        // A session that expires again before anything was sent won't get
+        // any further by pairing once more
+        let expired = matches!(err.downcast_ref(), Some(ApiError::SessionExpired));
+        if !expired || (paired_again && uploads.paths.len() == sent_before) {
+            return Err(err);
+        }
+        tracing::warn!("{device_name}: session expired, pairing again to upload the rest");
+        device = Arc::new(pair_again(library, args, &device_id).await?);
+        paired_again = true;
+        selected.retain(|file| !uploads.paths.contains(&file.path));
+    }
+    if !args.quiet {
+        print_summary(
+            (&device_id, &device_name),
+            &uploads.counts,
+            upload_start.elapsed(),
+            args.output,
+        );
+    }
+    if let Err(err) = library
+        .mark_synced(&device_id, device.info(), started)
+        .await
+    {
+        tracing::warn!("Couldn't record sync time: {err:#}");
+    }
+    Ok(())
+}
+
+/// Narrows `filters` to files modified since the device was last synced to,
+/// for --since-last-sync.
+async fn since_last_sync(library: &Library, device_id: &str, filters: &Filters) -> Filters {
+    let last_synced = match library.last_synced(device_id).await {
+        Ok(Some(at)) => at,
+        Ok(None) => {
+            tracing::warn!("No earlier sync to this device was recorded, considering all files");
+            return filters.clone();
+        }
+        Err(err) => {
+            tracing::warn!("Couldn't read the last sync time, considering all files: {err:#}");
+            return filters.clone();
+        }
+    };
+    tracing::info!(
+        "Only considering files modified since the last sync ({})",
+        format_age(last_synced)
     );
+    let last_synced = SystemTime::UNIX_EPOCH + Duration::from_secs(last_synced.max(0) as u64);
+    Filters {
+        modified_since: Some(
+            filters
+                .modified_since
+                .map_or(last_synced, |m| m.max(last_synced)),
+        ),
+        ..filters.clone()
+    }
+}
 
-    tokio::spawn(process_all_paths(
-        device.clone(),
+/// Saves the device if it asked to be, and records where it was reached.
+async fn save_device(library: &Library, connected: &Connected) -> anyhow::Result<()> {
+    let device = &connected.client;
+    if connected.save {
+        if let Some(push_token) = device.push_token() {
+            tracing::info!("Saving device per its request");
+            library
+                .add_device(push_token)
+                .await
+                .context("Couldn't save device to database")?;
+        }
+    }
+    // Does nothing for devices that aren't saved
+    if let Err(err) = library.set_lan_url(&connected.id, device.lan_url()).await {
+        tracing::warn!("Couldn't record the device's LAN URL: {err:#}");
+    }
+    Ok(())
+}
+
+/// The files uploaded during a sync.
+#[derive(Default)]
+struct Uploads {
+    /// The number and size of the files uploaded by type.
+    counts: BTreeMap<String, TypeCount>,
+    /// The selected files that were uploaded, so a sync resumed after pairing
+    /// again doesn't send them twice.
+    paths: HashSet<PathBuf>,
+}
+
+/// Uploads the selected files, recording each one uploaded in the library and
+/// in `uploads`.
+///
+/// Stops starting uploads at the first file that fails, returning its error
+/// once the uploads already underway have finished.
+async fn upload_files(
+    library: &Library,
+    args: &Args,
+    device: &Arc<DeviceClient>,
+    device_id: &str,
+    selected: Vec<SelectedFile>,
+    limits: &TaskLimits,
+    uploads: &mut Uploads,
+) -> anyhow::Result<()> {
+    // Files already uploaded by name were dealt with when selecting them,
+    // except for those to be renamed
+    let taken = if args.on_conflict == OnConflict::Rename {
+        library
+            .uploaded_names(device_id)
+            .await
+            .unwrap_or_else(|err| {
+                tracing::warn!("Couldn't read previous uploads: {err:#}");
+                Default::default()
+            })
+    } else {
+        HashSet::new()
+    };
+    let selected = upload_names(
         selected,
+        args.name_template.as_ref(),
+        args.on_conflict,
+        taken,
+    );
+    let file_count = selected.len();
+    let total_bytes = selected.iter().map(|(file, _)| file.len).sum();
+    let settings = UploadSettings {
+        retries: args.retries,
+        backoff: backoff(args),
+        error_log: args.error_log.as_deref().map(ErrorLog::open).transpose()?,
+        hooks: Hooks::new(args.pre_upload_cmd.clone(), args.post_upload_cmd.clone()),
+        mime_overrides: MimeOverrides::new(args.force_mime.clone(), &args.mime_map),
+    };
+    let (send, mut recv) = mpsc::channel::<anyhow::Result<Uploaded>>(1);
+
+    let (events, shown) = progress::spawn(args.progress, args.progress_style);
+    let _ = events.send(SyncEvent::Selected {
+        files: file_count,
+        bytes: total_bytes,
+    });
+
+    #[cfg(feature = "batch")]
+    let batch_size = args.batch_size.into();
+    #[cfg(not(feature = "batch"))]
+    let batch_size = 1;
+    let processing = tokio::spawn(process_all_paths(
+        device.clone(),
+        batch_files(selected, batch_size),
         send,
-        args.tasks as usize,
-        progress.clone(),
+        limits.clone(),
+        Arc::new(settings),
+        events.clone(),
     ));
-    if let Some(err) = recv.recv().await {
-        progress.abandon();
-        Err(err)
-    } else {
-        progress.finish_and_clear();
-        Ok(())
+    let mut failed = None;
+    while let Some(result) = recv.recv().await {
+        match result {
+            Ok(uploaded) => {
+                let name = &uploaded.name;
+                if !uploaded.confirmed {
+                    tracing::debug!("{name}: sent in a batch, not recording it as uploaded");
+                } else if let Err(err) = library
+                    .record_upload(device_id, name, &uploaded.sha256)
+                    .await
+                {
+                    tracing::warn!("Couldn't record upload of {name}: {err:#}");
+                }
+                let count = uploads
+                    .counts
+                    .entry(uploaded.mime.essence_str().to_string())
+                    .or_default();
+                count.files += 1;
+                count.bytes += uploaded.bytes;
+                uploads.paths.insert(uploaded.path);
+            }
+            // Stop starting uploads, but keep recording those already
+            // underway until they finish, so a sync resumed after pairing
+            // again doesn't send them twice
+            Err(err) if failed.is_none() => {
+                processing.abort();
+                failed = Some(err);
+            }
+            Err(err) => tracing::error!("{err:#}"),
+        }
+    }
+    if let Some(err) = failed {
+        let _ = events.send(SyncEvent::Done { complete: false });
+        let _ = shown.await;
+        return Err(err);
+    }
+    let _ = events.send(SyncEvent::Done { complete: true });
+    let _ = shown.await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> Result<u64, String> {
+        let time = parse_cutoff(value)?;
+        Ok(time
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs())
+    }
+
+    #[test]
+    fn cutoff_dates_are_midnight_utc() {
+        assert_eq!(date("1970-01-01"), Ok(0));
+        assert_eq!(date("2024-08-27"), Ok(1_724_716_800));
+        assert_eq!(date("2024-02-29"), Ok(1_709_164_800));
+    }
+
+    #[test]
+    fn cutoff_days_past_the_end_of_the_month_are_invalid() {
+        assert!(date("2024-02-30").is_err());
+        assert!(date("2023-02-29").is_err());
+        assert!(date("1900-02-29").is_err());
+        assert!(date("2024-04-31").is_err());
+        assert!(date("2024-02-31").is_err());
+        assert!(date("2000-02-29").is_ok());
     }
 }
@@ -1,12 +1,76 @@
-use std::ops::{Deref, DerefMut};
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::{Deref, DerefMut},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
-use indicatif::ProgressBar;
+use clap::ValueEnum;
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::{sync::mpsc, task::JoinHandle};
 
-use crate::ProgressMode;
+use crate::{
+    events::{EventSender, SyncEvent, UploadId},
+    tags::file_label,
+    ProgressMode,
+};
+
+/// The look of the upload progress bar.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum BarStyle {
+    /// A bar with the number of files uploaded.
+    #[default]
+    Default,
+    /// The message, elapsed time, rate and estimated time remaining.
+    Detailed,
+    /// Just a spinner and the number of files uploaded.
+    Minimal,
+}
+
+impl BarStyle {
+    /// Gets the indicatif template for the style, counting either bytes or
+    /// files.
+    fn template(self, by_bytes: bool) -> &'static str {
+        match (self, by_bytes) {
+            (Self::Default, false) => "{wide_bar} {pos}/{len} ({eta}) {msg}",
+            (Self::Default, true) => "{wide_bar} {bytes}/{total_bytes} ({eta}) {msg}",
+            (Self::Detailed, false) => {
+                "{msg}\n{spinner} [{elapsed_precise}] {wide_bar} {pos}/{len} ({per_sec}, {eta} left)"
+            }
+            (Self::Detailed, true) => {
+                "{msg}\n{spinner} [{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} ({bytes_per_sec}, {eta} left)"
+            }
+            (Self::Minimal, false) => "{spinner} {pos}/{len}",
+            (Self::Minimal, true) => "{spinner} {bytes}/{total_bytes}",
+        }
+    }
+}
+
+impl fmt::Display for BarStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Default => "default",
+            Self::Detailed => "detailed",
+            Self::Minimal => "minimal",
+        }
+        .fmt(f)
+    }
+}
+
+/// How many in-flight file names are shown before the rest are summarized.
+const MAX_ACTIVE_SHOWN: usize = 2;
 
 #[derive(Clone)]
 pub struct Progression {
     bar: ProgressBar,
+    /// The message shown before the names of in-flight files.
+    label: Arc<str>,
+    /// Names of the files currently being worked on.
+    active: Arc<Mutex<Vec<String>>>,
+    /// Whether the bar counts bytes rather than files.
+    by_bytes: bool,
 }
 
 impl Progression {
@@ -16,17 +80,170 @@ impl Progression {
         } else {
             ProgressBar::hidden()
         };
-        Self { bar }
+        Self::from_bar(bar)
     }
 
-    pub fn new(mode: ProgressMode, len: u64, message: impl Into<String>) -> Self {
+    /// Creates a bar for `len` files. If the total size of the files is
+    /// known, progress is counted in bytes instead, so the rate and time
+    /// remaining are more accurate.
+    pub fn new(
+        mode: ProgressMode,
+        style: BarStyle,
+        len: u64,
+        total_bytes: Option<u64>,
+        message: impl Into<String>,
+    ) -> Self {
+        let by_bytes = total_bytes.is_some();
         let bar = if mode == ProgressMode::On {
-            ProgressBar::new(len).with_message(message.into())
+            ProgressBar::new(total_bytes.unwrap_or(len))
+                .with_style(ProgressStyle::with_template(style.template(by_bytes)).unwrap())
+                .with_message(message.into())
         } else {
             ProgressBar::hidden()
         };
-        Self { bar }
+        Self {
+            by_bytes,
+            ..Self::from_bar(bar)
+        }
+    }
+
+    fn from_bar(bar: ProgressBar) -> Self {
+        Self {
+            label: bar.message().into(),
+            bar,
+            active: Arc::default(),
+            by_bytes: false,
+        }
+    }
+
+    /// Adds a file to those shown as in flight.
+    pub fn start_file(&self, name: impl Into<String>) {
+        let mut active = self.active.lock().unwrap();
+        active.push(name.into());
+        self.show_active(&active);
+    }
+
+    /// Removes a file from those shown as in flight.
+    pub fn finish_file(&self, name: &str) {
+        let mut active = self.active.lock().unwrap();
+        if let Some(idx) = active.iter().position(|n| n == name) {
+            active.remove(idx);
+        }
+        self.show_active(&active);
+    }
+
+    /// Updates the message with the in-flight files, such as
+    /// "Uploading 5 files: a.mp3, b.flac (+3)".
+    fn show_active(&self, active: &[String]) {
+        if active.is_empty() {
+            self.bar.set_message(self.label.to_string());
+            return;
+        }
+        let mut message = format!(
+            "{}: {}",
+            self.label,
+            active[..active.len().min(MAX_ACTIVE_SHOWN)].join(", ")
+        );
+        if active.len() > MAX_ACTIVE_SHOWN {
+            message.push_str(&format!(" (+{})", active.len() - MAX_ACTIVE_SHOWN));
+        }
+        self.bar.set_message(message);
+    }
+}
+
+/// Starts showing the events sent to the returned sender, until they're
+/// done or the sender is dropped.
+pub fn spawn(mode: ProgressMode, style: BarStyle) -> (EventSender, JoinHandle<()>) {
+    let (events, event_recv) = mpsc::unbounded_channel();
+    let shown = tokio::spawn(show(mode, style, event_recv));
+    (events, shown)
+}
+
+/// Shows a spinner while looking for files, then a bar for a run of uploads
+/// from its events, until it's done.
+pub async fn show(
+    mode: ProgressMode,
+    style: BarStyle,
+    mut events: mpsc::UnboundedReceiver<SyncEvent>,
+) {
+    let mut progress = Progression::from_bar(ProgressBar::hidden());
+    // The directory being scanned, if any
+    let mut scanning: Option<Arc<Path>> = None;
+    // The label of each upload in flight, and the bytes its current attempt
+    // has sent
+    let mut active = HashMap::<UploadId, (String, u64)>::new();
+    while let Some(event) = events.recv().await {
+        match event {
+            SyncEvent::Scanning { dir, found } => {
+                if !scanning.as_ref().is_some_and(|d| Arc::ptr_eq(d, &dir)) {
+                    progress.finish_and_clear();
+                    progress = Progression::new_spinner(
+                        mode,
+                        format!("Finding music files for {}", dir.display()),
+                    );
+                    progress.set_style(
+                        ProgressStyle::with_template("{spinner} {msg}... {human_pos} scanned")
+                            .unwrap(),
+                    );
+                    progress.enable_steady_tick(Duration::from_millis(300));
+                    scanning = Some(dir);
+                }
+                progress.set_position(found as u64);
+            }
+            SyncEvent::Selected { files, bytes } => {
+                progress.finish_and_clear();
+                let message = format!("Uploading {files} files");
+                progress = Progression::new(mode, style, files as u64, bytes, message);
+            }
+            SyncEvent::FileStarted { id, name, path } => {
+                let label = match path {
+                    Some(path) if !progress.is_hidden() => file_label(path, name).await,
+                    _ => name,
+                };
+                progress.start_file(label.clone());
+                active.insert(id, (label, 0));
+            }
+            SyncEvent::FileRequeued { id } => {
+                if let Some((label, sent)) = active.remove(&id) {
+                    // Take back what the failed attempt sent
+                    progress.finish_file(&label);
+                    let position = progress.position();
+                    progress.set_position(position.saturating_sub(sent));
+                }
+            }
+            SyncEvent::FileProgress { id, bytes } => {
+                if progress.by_bytes {
+                    if let Some((_, sent)) = active.get_mut(&id) {
+                        *sent += bytes;
+                    }
+                    progress.inc(bytes);
+                }
+            }
+            SyncEvent::FileDone { id, files, bytes }
+            | SyncEvent::FileFailed { id, files, bytes } => {
+                let sent = active.remove(&id).map_or(0, |(label, sent)| {
+                    progress.finish_file(&label);
+                    sent
+                });
+                // Count the rest whether or not it was sent, so the bar still
+                // reaches the end
+                if progress.by_bytes {
+                    progress.inc(bytes.saturating_sub(sent));
+                } else {
+                    progress.inc(files);
+                }
+            }
+            SyncEvent::Done { complete: true } => {
+                progress.finish_and_clear();
+                return;
+            }
+            SyncEvent::Done { complete: false } => {
+                progress.abandon();
+                return;
+            }
+        }
     }
+    progress.finish_and_clear();
 }
 
 impl Deref for Progression {
@@ -0,0 +1,95 @@
+//! Forgetting saved devices that no longer respond, for --prune-devices.
+
+use std::time::{Duration, Instant};
+
+use doppler_ws::{error::ApiError, model::Device, TransferClient};
+
+use crate::{connect_direct, db::Library, open_api, progress::Progression, Args};
+
+/// How long each device has to respond to its notification.
+const RESPOND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Checks every saved device and forgets the ones that don't respond, or
+/// only lists them with --dry-run.
+pub async fn run(library: &Library, args: &Args) -> anyhow::Result<()> {
+    let records = library.devices().await?;
+    if records.is_empty() {
+        println!("No devices are saved.");
+        return Ok(());
+    }
+
+    // Only connected once a device needs a notification
+    let mut api = None;
+    let mut missing = Vec::new();
+    for record in records {
+        let name = record.name;
+        let Some(device) = library.get_device(&name).await? else {
+            continue;
+        };
+        if connect_direct(library, args, &device).await.is_some() {
+            println!("{name}: reachable on the network");
+            continue;
+        }
+
+        let api = match &mut api {
+            Some(api) => api,
+            None => api.insert(open_api(library, args).await?),
+        };
+        let spin =
+            Progression::new_spinner(args.progress, format!("Waiting for {name} to respond..."));
+        spin.enable_steady_tick(Duration::from_millis(300));
+        let result = responds(api, &device).await;
+        spin.finish_and_clear();
+        match result {
+            Ok(true) => println!("{name}: responded"),
+            Ok(false) => {
+                println!(
+                    "{name}: didn't respond within {} seconds",
+                    RESPOND_TIMEOUT.as_secs()
+                );
+                missing.push(name);
+            }
+            // Keep devices that couldn't be checked
+            Err(err) => tracing::warn!("Couldn't check {name}: {err:#}"),
+        }
+    }
+    if let Some(api) = api {
+        if let Err(err) = api.close().await {
+            tracing::debug!("Error closing pairing connection: {err}");
+        }
+    }
+
+    if missing.is_empty() {
+        println!("All saved devices responded.");
+    } else if args.dry_run {
+        println!("Would forget {}.", missing.join(", "));
+    } else {
+        for name in &missing {
+            library.delete_device(name).await?;
+            println!("Device {name} forgotten.");
+        }
+    }
+    Ok(())
+}
+
+/// Sends the device a notification and waits for it to respond. A device
+/// that does is paired with, so the app isn't left waiting.
+async fn responds(api: &mut TransferClient, device: &Device) -> anyhow::Result<bool> {
+    api.resend_push(device).await?;
+    let deadline = Instant::now() + RESPOND_TIMEOUT;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        match api.wait_for_saved_device(device, Some(left)).await {
+            Ok(response) => {
+                if let Err(err) = api.confirm(&response, true).await {
+                    tracing::debug!("Couldn't reach {}: {err}", device.display_name());
+                }
+                return Ok(true);
+            }
+            Err(ApiError::Timeout(_)) => return Ok(false),
+            // A device checked earlier that responded late
+            Err(ApiError::UnexpectedDevice) => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
@@ -0,0 +1,635 @@
+//! Selection of the files to upload from the path arguments.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context};
+use doppler_ws::device::{self, DeviceClient};
+use indicatif::HumanBytes;
+use mime_guess::Mime;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    events::{EventSender, SyncEvent},
+    tags::Tags,
+};
+
+/// Decides which MIME types can be uploaded.
+pub trait MimeSupport: Sync {
+    /// Checks whether files of type `mime` are accepted. With `strict`, the
+    /// type must be listed exactly, without the `x-` prefix fallback.
+    fn supports(&self, mime: &Mime, strict: bool) -> bool;
+}
+
+impl MimeSupport for DeviceClient {
+    fn supports(&self, mime: &Mime, strict: bool) -> bool {
+        if strict {
+            self.mime_supported_strict(mime)
+        } else {
+            self.mime_supported(mime)
+        }
+    }
+}
+
+/// The types Doppler commonly supports, for selecting files without a paired
+/// device.
+pub struct CommonTypes;
+
+impl MimeSupport for CommonTypes {
+    fn supports(&self, mime: &Mime, strict: bool) -> bool {
+        if strict {
+            device::COMMON_MIMETYPES.contains(&mime.essence_str())
+        } else {
+            device::mime_commonly_supported(mime)
+        }
+    }
+}
+
+/// Why a file found while scanning wasn't selected for upload.
+#[derive(Debug)]
+pub enum SkipReason {
+    /// No MIME type could be guessed from the file name.
+    UnknownType,
+    /// The guessed MIME type isn't supported by the device.
+    UnsupportedType(Mime),
+    /// The file is larger than `--max-file-size`.
+    TooLarge(u64),
+    /// The file wasn't modified after `--modified-since`.
+    NotModified,
+    /// The file is missing tags required by `--require-tags`.
+    MissingTags(Vec<&'static str>),
+}
+
+impl SkipReason {
+    /// Whether the file was excluded by one of the [`Filters`], rather than
+    /// being unsupported.
+    fn is_filtered(&self) -> bool {
+        matches!(
+            self,
+            Self::TooLarge(_) | Self::NotModified | Self::MissingTags(_)
+        )
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownType => write!(f, "unknown file type"),
+            Self::UnsupportedType(mime) => write!(f, "unsupported type {mime}"),
+            Self::TooLarge(len) => write!(f, "file too large ({})", HumanBytes(*len)),
+            Self::NotModified => write!(f, "not modified since the cutoff"),
+            Self::MissingTags(tags) => write!(f, "missing tags: {}", tags.join(", ")),
+        }
+    }
+}
+
+/// What came of a single path argument during selection.
+#[derive(Debug)]
+pub enum PathOutcome {
+    /// This many files were selected from the path.
+    Selected(usize),
+    /// The path doesn't exist, or is a glob pattern that matched nothing.
+    Missing,
+    /// The path is a directory, but recursion wasn't enabled.
+    NotRecursed,
+    /// Files were found, but none of them are supported by the device.
+    NoneSupported { scanned: usize },
+}
+
+impl fmt::Display for PathOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Selected(count) => write!(f, "{count} files selected"),
+            Self::Missing => write!(f, "path does not exist"),
+            Self::NotRecursed => write!(f, "directory skipped (use -r to recurse)"),
+            Self::NoneSupported { scanned } => {
+                write!(f, "no supported files among {scanned} scanned")
+            }
+        }
+    }
+}
+
+/// Which device each file is uploaded to, from --route. Files whose extension
+/// isn't routed go to the devices given with --device.
+#[derive(Debug, Default)]
+pub struct Routes {
+    /// IDs of the devices by lowercase file extension.
+    by_ext: HashMap<String, String>,
+    /// IDs of the devices that get files whose extension isn't routed.
+    defaults: HashSet<String>,
+}
+
+impl Routes {
+    /// Starts with no routes, sending unrouted files to the devices with the
+    /// given IDs.
+    pub fn new(defaults: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            by_ext: HashMap::new(),
+            defaults: defaults.into_iter().collect(),
+        }
+    }
+
+    /// Sends files with the extension `ext` to the device with ID `device_id`.
+    pub fn add(&mut self, ext: String, device_id: String) {
+        self.by_ext.insert(ext, device_id);
+    }
+
+    /// Checks whether the file at `path` goes to the device with ID
+    /// `device_id`. Without any routes, every file goes to every device.
+    pub fn accepts(&self, path: &Path, device_id: &str) -> bool {
+        if self.by_ext.is_empty() {
+            return true;
+        }
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        match ext.and_then(|ext| self.by_ext.get(&ext)) {
+            Some(id) => id == device_id,
+            None => self.defaults.contains(device_id),
+        }
+    }
+}
+
+/// Conditions a file must meet to be selected, beyond being supported by the
+/// device.
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    /// Skip files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Skip files last modified before this time.
+    pub modified_since: Option<SystemTime>,
+    /// Only accept MIME types exactly as listed by the device.
+    pub strict_mime: bool,
+    /// Skip files without artist and title tags.
+    pub require_tags: bool,
+    /// Fail on unsupported files found in directories or through glob
+    /// patterns, instead of skipping them.
+    pub strict: bool,
+    /// Types to use instead of guessing from file names.
+    pub mime_overrides: MimeOverrides,
+}
+
+/// MIME types given on the command line in place of guessing them from file
+/// names, from --force-mime and --mime-map. These are still checked against
+/// what the device supports.
+#[derive(Clone, Debug, Default)]
+pub struct MimeOverrides {
+    /// The type of every file.
+    force: Option<Mime>,
+    /// Types by lowercase file extension.
+    by_ext: HashMap<String, Mime>,
+}
+
+impl MimeOverrides {
+    pub fn new(force: Option<Mime>, by_ext: &[(String, Mime)]) -> Self {
+        Self {
+            force,
+            by_ext: by_ext.iter().cloned().collect(),
+        }
+    }
+
+    /// Gets the possible types of the file at `path`, most likely first.
+    pub fn guess(&self, path: &Path) -> Vec<Mime> {
+        if let Some(mime) = &self.force {
+            return vec![mime.clone()];
+        }
+        let ext = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase());
+        if let Some(mime) = ext.and_then(|ext| self.by_ext.get(&ext)) {
+            return vec![mime.clone()];
+        }
+        mime_guess::from_path(path).iter().collect()
+    }
+}
+
+/// How many of the selected files have a given type, and their combined size.
+#[derive(Debug, Default)]
+pub struct TypeCount {
+    pub files: usize,
+    pub bytes: u64,
+}
+
+/// A file chosen for upload.
+#[derive(Clone, Debug)]
+pub struct SelectedFile {
+    pub path: PathBuf,
+    pub mime: Mime,
+    /// The file's size when it was selected, or `None` if it couldn't be
+    /// read. It's kept so the file isn't read again for it.
+    pub len: Option<u64>,
+}
+
+/// The files chosen for upload, along with what was left out and why.
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub files: Vec<SelectedFile>,
+    pub skipped: Vec<(PathBuf, SkipReason)>,
+    pub outcomes: Vec<(PathBuf, PathOutcome)>,
+}
+
+impl Selection {
+    /// Selects the files supported by the device from the path arguments.
+    ///
+    /// Explicitly named files that aren't supported are an error. Files found
+    /// in directories or through glob patterns are skipped instead, unless
+    /// `filters.strict` is set. Files excluded by the `filters` are always
+    /// skipped. How scanning directories is going is sent to `events`.
+    pub async fn from_args(
+        device: &impl MimeSupport,
+        args: Vec<PathBuf>,
+        recurse: bool,
+        filters: &Filters,
+        events: &EventSender,
+    ) -> anyhow::Result<Self> {
+        let mut selection = Self::default();
+        for arg in args {
+            // Expand any glob patterns the shell didn't
+            let (candidates, explicit) = if !arg.exists() && is_glob(&arg) {
+                (expand_glob(&arg)?, false)
+            } else if arg.exists() {
+                (vec![arg.clone()], true)
+            } else {
+                (Vec::new(), true)
+            };
+            if candidates.is_empty() {
+                selection.outcomes.push((arg, PathOutcome::Missing));
+                continue;
+            }
+
+            let before = selection.files.len();
+            let mut scanned = 0;
+            let mut not_recursed = false;
+            for path in candidates {
+                if path.is_dir() {
+                    if !recurse {
+                        not_recursed = true;
+                        continue;
+                    }
+                    let paths = scan_dir(path, events).await?;
+                    scanned += paths.len();
+                    // Guessing and filtering means reading metadata for
+                    // every file, so spread it across threads. The order
+                    // of the paths is kept.
+                    let checked: Vec<_> = tokio::task::block_in_place(|| {
+                        paths
+                            .into_par_iter()
+                            .map(|path| {
+                                let result = select_file(device, &path, filters);
+                                (path, result)
+                            })
+                            .collect()
+                    });
+                    for (path, result) in checked {
+                        match result {
+                            Ok(file) => selection.files.push(file),
+                            Err(reason) => selection.skipped.push((path, reason)),
+                        }
+                    }
+                } else {
+                    scanned += 1;
+                    match select_file(device, &path, filters) {
+                        Ok(file) => selection.files.push(file),
+                        // Files matched by a glob are treated like those found
+                        // while recursing, rather than as explicitly requested
+                        Err(reason) if !explicit || reason.is_filtered() => {
+                            selection.skipped.push((path, reason))
+                        }
+                        Err(_) => bail!("{}: unsupported mime type", path.display()),
+                    }
+                }
+            }
+
+            let selected = selection.files.len() - before;
+            let outcome = if selected > 0 {
+                PathOutcome::Selected(selected)
+            } else if not_recursed && scanned == 0 {
+                PathOutcome::NotRecursed
+            } else {
+                PathOutcome::NoneSupported { scanned }
+            };
+            selection.outcomes.push((arg, outcome));
+        }
+
+        if filters.strict {
+            let unsupported: Vec<_> = selection
+                .skipped
+                .iter()
+                .filter(|(_, reason)| !reason.is_filtered())
+                .map(|(path, reason)| format!("  {}: {reason}", path.display()))
+                .collect();
+            if !unsupported.is_empty() {
+                bail!(
+                    "{} unsupported files found:\n{}",
+                    unsupported.len(),
+                    unsupported.join("\n")
+                );
+            }
+        }
+
+        selection.dedup_files();
+        Ok(selection)
+    }
+
+    /// Counts the selected files by MIME type. Files whose size can't be
+    /// read are counted as empty.
+    pub fn count_by_mime(&self) -> BTreeMap<String, TypeCount> {
+        let mut counts = BTreeMap::<_, TypeCount>::new();
+        for file in &self.files {
+            let count = counts
+                .entry(file.mime.essence_str().to_string())
+                .or_default();
+            count.files += 1;
+            count.bytes += file.len.unwrap_or(0);
+        }
+        counts
+    }
+
+    /// Canonicalizes the selected paths and removes duplicates, which happen
+    /// when path arguments overlap. Otherwise the same file would be uploaded
+    /// more than once.
+    fn dedup_files(&mut self) {
+        let mut seen = HashSet::new();
+        let files = std::mem::take(&mut self.files);
+        for mut file in files {
+            // If the path can't be resolved, leave it to the upload to report why
+            if let Ok(path) = std::fs::canonicalize(&file.path) {
+                file.path = path;
+            }
+            if seen.insert(file.path.clone()) {
+                self.files.push(file);
+            } else {
+                tracing::debug!("{}: selected more than once", file.path.display());
+            }
+        }
+    }
+}
+
+/// Checks whether a file should be uploaded, returning it with its type and
+/// size if so.
+pub fn select_file(
+    device: &impl MimeSupport,
+    path: &Path,
+    filters: &Filters,
+) -> Result<SelectedFile, SkipReason> {
+    let mime = supported_mime(device, path, filters)?;
+    // If the metadata can't be read, leave it to the upload to report why
+    let meta = std::fs::metadata(path).ok();
+    let len = meta.as_ref().map(|meta| meta.len());
+    if let (Some(max), Some(len)) = (filters.max_file_size, len) {
+        if len > max {
+            tracing::warn!(
+                "{}: skipping, {} is over the maximum file size",
+                path.display(),
+                HumanBytes(len)
+            );
+            return Err(SkipReason::TooLarge(len));
+        }
+    }
+    if let (Some(cutoff), Some(meta)) = (filters.modified_since, &meta) {
+        if meta.modified().is_ok_and(|modified| modified <= cutoff) {
+            return Err(SkipReason::NotModified);
+        }
+    }
+
+    check_tags(path, filters)?;
+    Ok(SelectedFile {
+        path: path.to_path_buf(),
+        mime,
+        len,
+    })
+}
+
+/// Checks that a file has the tags Doppler sorts by, if `--require-tags` was
+/// given. Files whose tags can't be read count as untagged.
+fn check_tags(path: &Path, filters: &Filters) -> Result<(), SkipReason> {
+    if !filters.require_tags {
+        return Ok(());
+    }
+    let missing = match Tags::read(path) {
+        Ok(tags) => tags.missing(),
+        Err(err) => {
+            tracing::debug!("{}: couldn't read tags: {err:#}", path.display());
+            vec!["artist", "title"]
+        }
+    };
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        tracing::warn!(
+            "{}: skipping, missing tags: {}",
+            path.display(),
+            missing.join(", ")
+        );
+        Err(SkipReason::MissingTags(missing))
+    }
+}
+
+/// Finds the first guessed MIME type for the path that the device supports.
+fn supported_mime(
+    device: &impl MimeSupport,
+    path: &Path,
+    filters: &Filters,
+) -> Result<Mime, SkipReason> {
+    let mut guess = filters.mime_overrides.guess(path);
+    if let Some(i) = guess
+        .iter()
+        .position(|mime| device.supports(mime, filters.strict_mime))
+    {
+        Ok(guess.swap_remove(i))
+    } else if guess.is_empty() {
+        Err(SkipReason::UnknownType)
+    } else {
+        Err(SkipReason::UnsupportedType(guess.swap_remove(0)))
+    }
+}
+
+/// Gets the SHA-256 of a file's contents as lowercase hex, matching the hash
+/// reported for an upload of it.
+pub fn file_sha256(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Reads the paths listed in a manifest for --from-file, one per line. Blank
+/// lines and lines starting with `#` are ignored.
+///
+/// Relative paths are taken from the manifest's folder rather than the
+/// current one, so a manifest works wherever it's used from. A leading `~/`
+/// is expanded to the home directory. Lines may be glob patterns.
+pub fn read_manifest(manifest: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let text = std::fs::read_to_string(manifest)
+        .with_context(|| format!("Couldn't read {}", manifest.display()))?;
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    let home = dirs::home_dir();
+    let paths: Vec<_> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match (line.strip_prefix("~/"), &home) {
+            (Some(rest), Some(home)) => home.join(rest),
+            _ => base.join(line),
+        })
+        .collect();
+    if paths.is_empty() {
+        bail!("{} doesn't list any paths", manifest.display());
+    }
+    Ok(paths)
+}
+
+/// Checks whether a path argument contains glob metacharacters.
+fn is_glob(path: &Path) -> bool {
+    path.to_str().is_some_and(|p| p.contains(['*', '?', '[']))
+}
+
+/// Expands a glob pattern into the paths it matches. A leading `~/` is
+/// expanded to the home directory, since quoting the pattern stops the shell
+/// from doing so.
+fn expand_glob(pattern: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let Some(mut pattern) = pattern.to_str().map(String::from) else {
+        bail!("Glob pattern {} is not UTF-8", pattern.display());
+    };
+    if let Some(rest) = pattern.strip_prefix("~/") {
+        if let Some(home) = dirs::home_dir().and_then(|h| h.to_str().map(String::from)) {
+            pattern = format!("{home}/{rest}");
+        }
+    }
+
+    let mut paths = Vec::new();
+    for entry in glob::glob(&pattern).with_context(|| format!("Invalid glob pattern {pattern}"))? {
+        paths.push(entry?);
+    }
+
+    Ok(paths)
+}
+
+/// Recursively gets all file paths in a directory, sending how many files
+/// have been found so far to `events` while doing so.
+async fn scan_dir(dir: PathBuf, events: &EventSender) -> anyhow::Result<Vec<PathBuf>> {
+    let display = dir.display().to_string();
+    let events = events.clone();
+    tokio::task::spawn_blocking(move || {
+        let dir: Arc<Path> = dir.into();
+        let found = |found| {
+            let _ = events.send(SyncEvent::Scanning {
+                dir: dir.clone(),
+                found,
+            });
+        };
+        found(0);
+        get_dir_paths(&dir, &found)
+    })
+    .await
+    .with_context(|| format!("while recursing {display}"))?
+}
+
+/// Recursively get all file paths in a directory, calling `found` with the
+/// number of files found so far as each one is found.
+///
+/// Only failing to read `dir` itself is an error. Entries below it that can't
+/// be read are logged and skipped, and symlinks leading back to a directory
+/// that was already visited aren't followed again.
+fn get_dir_paths(dir: &Path, found: &dyn Fn(usize)) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut visited = HashSet::new();
+    if dir.is_dir() {
+        visited.insert(std::fs::canonicalize(dir)?);
+        let entries =
+            std::fs::read_dir(dir).with_context(|| format!("while recursing {}", dir.display()))?;
+        walk_entries(dir, entries, &mut visited, &mut paths, found);
+    }
+
+    Ok(paths)
+}
+
+/// Collects the files from a directory's entries, descending into
+/// subdirectories not yet in `visited`.
+fn walk_entries(
+    dir: &Path,
+    entries: std::fs::ReadDir,
+    visited: &mut HashSet<PathBuf>,
+    paths: &mut Vec<PathBuf>,
+    found: &dyn Fn(usize),
+) {
+    tracing::trace!("reading dir {}", dir.display());
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(err) => {
+                tracing::warn!("{}: {err}", dir.display());
+                continue;
+            }
+        };
+        if !path.is_dir() {
+            paths.push(path);
+            found(paths.len());
+            continue;
+        }
+
+        match std::fs::canonicalize(&path) {
+            Ok(canonical) => {
+                if !visited.insert(canonical) {
+                    tracing::debug!("{}: already visited, skipping", path.display());
+                    continue;
+                }
+            }
+            Err(err) => {
+                tracing::warn!("{}: {err}", path.display());
+                continue;
+            }
+        }
+        match std::fs::read_dir(&path) {
+            Ok(entries) => walk_entries(&path, entries, visited, paths, found),
+            Err(err) => tracing::warn!("{}: {err}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn file_inside_a_directory_argument_is_selected_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let song = dir.path().join("song.mp3");
+        std::fs::write(&song, b"").unwrap();
+        std::fs::write(dir.path().join("other.mp3"), b"").unwrap();
+        let (events, _rx) = mpsc::unbounded_channel();
+
+        let args = vec![dir.path().to_path_buf(), song.clone(), song.clone()];
+        let selection =
+            Selection::from_args(&CommonTypes, args, true, &Filters::default(), &events)
+                .await
+                .unwrap();
+
+        let mut paths: Vec<_> = selection.files.iter().map(|file| &file.path).collect();
+        paths.sort();
+        let dir = std::fs::canonicalize(dir.path()).unwrap();
+        assert_eq!(paths, [&dir.join("other.mp3"), &dir.join("song.mp3")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_to_an_ancestor_directory_is_not_followed_again() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        std::fs::create_dir(&sub).unwrap();
+        std::fs::write(dir.path().join("a.mp3"), b"").unwrap();
+        std::fs::write(sub.join("b.mp3"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.path(), sub.join("loop")).unwrap();
+
+        let mut paths = get_dir_paths(dir.path(), &|_| {}).unwrap();
+        paths.sort();
+        assert_eq!(paths, [dir.path().join("a.mp3"), sub.join("b.mp3")]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Checks that radarsync can run on this machine, for --selftest.
+
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use doppler_ws::{HeaderValue, TransferClientBuilder};
+
+use crate::{config::Config, db::Library, OutputFormat, USER_AGENT};
+
+/// How long to wait for the pairing API before failing its check.
+const API_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// The result of a single check.
+struct Check {
+    name: &'static str,
+    result: anyhow::Result<String>,
+}
+
+/// Runs every check and prints the results. Fails if any check failed.
+pub async fn run(output: OutputFormat) -> anyhow::Result<()> {
+    let checks = [
+        Check {
+            name: "data directory",
+            result: check_data_dir(),
+        },
+        Check {
+            name: "config file",
+            result: check_config(),
+        },
+        Check {
+            name: "library database",
+            result: check_library().await,
+        },
+        Check {
+            name: "pairing API",
+            result: check_api().await,
+        },
+    ];
+
+    let versions = [
+        ("radarsync", env!("CARGO_PKG_VERSION")),
+        ("doppler-ws", doppler_ws::VERSION),
+        ("os", std::env::consts::OS),
+        ("arch", std::env::consts::ARCH),
+    ];
+    match output {
+        OutputFormat::Text => {
+            for (name, version) in versions {
+                println!("{name}: {version}");
+            }
+            for check in &checks {
+                match &check.result {
+                    Ok(detail) => println!("PASS  {}: {detail}", check.name),
+                    Err(err) => println!("FAIL  {}: {err:#}", check.name),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let checks: Vec<_> = checks
+                .iter()
+                .map(|check| match &check.result {
+                    Ok(detail) => serde_json::json!({
+                        "name": check.name,
+                        "ok": true,
+                        "detail": detail,
+                    }),
+                    Err(err) => serde_json::json!({
+                        "name": check.name,
+                        "ok": false,
+                        "error": format!("{err:#}"),
+                    }),
+                })
+                .collect();
+            let report = serde_json::json!({
+                "versions": serde_json::Map::from_iter(
+                    versions.map(|(name, version)| (name.to_string(), version.into()))
+                ),
+                "checks": checks,
+            });
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+    }
+
+    let failed = checks.iter().filter(|check| check.result.is_err()).count();
+    if failed > 0 {
+        bail!("{failed} of {} checks failed", checks.len());
+    }
+    Ok(())
+}
+
+/// Checks that the directory the library database lives in can be found.
+fn check_data_dir() -> anyhow::Result<String> {
+    let Some(dir) = dirs::data_dir() else {
+        bail!("couldn't find the data directory");
+    };
+    std::fs::metadata(&dir).with_context(|| format!("can't access {}", dir.display()))?;
+    Ok(dir.join("radarsync").display().to_string())
+}
+
+/// Checks that the config file, if there is one, can be parsed.
+fn check_config() -> anyhow::Result<String> {
+    Config::load()?;
+    Ok(match Config::path() {
+        Some(path) if path.exists() => path.display().to_string(),
+        _ => "none".to_string(),
+    })
+}
+
+/// Checks that the library database opens and is migrated.
+async fn check_library() -> anyhow::Result<String> {
+    let library = Library::open().await?;
+    let devices = library.devices().await?;
+    Ok(format!("{} saved devices", devices.len()))
+}
+
+/// Checks that the pairing API can be reached and hands out a code, then
+/// closes the connection without pairing.
+async fn check_api() -> anyhow::Result<String> {
+    let builder = TransferClientBuilder::default().user_agent(HeaderValue::from_static(USER_AGENT));
+    let api = tokio::time::timeout(API_TIMEOUT, builder.connect())
+        .await
+        .context("timed out")??;
+    if let Err(err) = api.close().await {
+        tracing::debug!("Error closing pairing connection: {err}");
+    }
+    Ok("connected and received a pairing code".to_string())
+}
@@ -0,0 +1,100 @@
+//! Reading the metadata tags embedded in music files.
+//!
+//! Doppler sorts its library by these tags, so files without them show up as
+//! "Unknown Artist". Files are only ever read here, never modified.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use symphonia::core::{
+    formats::FormatOptions,
+    io::MediaSourceStream,
+    meta::{MetadataOptions, StandardTagKey, Tag},
+    probe::Hint,
+};
+
+/// The tags radarsync cares about. Empty tags are treated as missing.
+#[derive(Debug, Default)]
+pub struct Tags {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+}
+
+impl Tags {
+    /// Reads the tags of the file at `path`.
+    ///
+    /// Tags from before the container, such as ID3v2 in MP3 files, are
+    /// preferred over those inside it.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let stream = MediaSourceStream::new(Box::new(File::open(path)?), Default::default());
+        let mut hint = Hint::new();
+        if let Some(ext) = path.extension().and_then(|ext| ext.to_str()) {
+            hint.with_extension(ext);
+        }
+        let mut probed = symphonia::default::get_probe().format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?;
+
+        let mut tags = Self::default();
+        if let Some(revision) = probed.metadata.get().as_ref().and_then(|m| m.current()) {
+            tags.fill(revision.tags());
+        }
+        if let Some(revision) = probed.format.metadata().current() {
+            tags.fill(revision.tags());
+        }
+        Ok(tags)
+    }
+
+    /// Fills in the tags that are still missing.
+    fn fill(&mut self, tags: &[Tag]) {
+        for tag in tags {
+            let field = match tag.std_key {
+                Some(StandardTagKey::Artist) => &mut self.artist,
+                Some(StandardTagKey::TrackTitle) => &mut self.title,
+                _ => continue,
+            };
+            let value = tag.value.to_string();
+            if field.is_none() && !value.trim().is_empty() {
+                *field = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    /// Gets the tags that are missing out of those Doppler needs to sort the
+    /// file, such as `["artist"]`.
+    pub fn missing(&self) -> Vec<&'static str> {
+        let mut missing = Vec::new();
+        if self.artist.is_none() {
+            missing.push("artist");
+        }
+        if self.title.is_none() {
+            missing.push("title");
+        }
+        missing
+    }
+
+    /// Gets a label such as "Artist - Title" for showing the file, if both
+    /// tags are set.
+    pub fn label(&self) -> Option<String> {
+        Some(format!(
+            "{} - {}",
+            self.artist.as_ref()?,
+            self.title.as_ref()?
+        ))
+    }
+}
+
+/// Gets how a file is shown while it's uploaded: "Artist - Title" from its
+/// tags if it has both, otherwise the name it's uploaded as.
+pub async fn file_label(path: PathBuf, name: String) -> String {
+    tokio::task::spawn_blocking(move || Tags::read(&path))
+        .await
+        .ok()
+        .and_then(|tags| tags.ok()?.label())
+        .unwrap_or(name)
+}
@@ -0,0 +1,119 @@
+//! Templates for the names files are uploaded to the device with.
+
+use std::path::Path;
+
+/// A piece of a name template.
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    /// The file name, with its extension.
+    Name,
+    /// The file name without its extension.
+    Stem,
+    /// The extension, without the dot.
+    Ext,
+    /// The name of the folder containing the file.
+    Parent,
+    /// The name of the folder containing `Parent`.
+    Grandparent,
+}
+
+/// A template for upload names, such as `{parent}-{name}`, parsed from
+/// `--name-template`.
+///
+/// The device only keeps the last component of the name, so templates can't
+/// contain a `/`.
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+impl NameTemplate {
+    /// Parses a template, checking that every placeholder is known.
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if value.contains('/') {
+            return Err("the device doesn't keep folders, so '/' can't be used".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut rest = value;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed '{{' in '{value}'"))?;
+            let part = match &rest[start + 1..start + end] {
+                "name" => Part::Name,
+                "stem" => Part::Stem,
+                "ext" => Part::Ext,
+                "parent" => Part::Parent,
+                "grandparent" => Part::Grandparent,
+                other => {
+                    return Err(format!(
+                        "unknown placeholder '{{{other}}}', expected one of {{name}}, \
+                         {{stem}}, {{ext}}, {{parent}} or {{grandparent}}"
+                    ))
+                }
+            };
+            parts.push(part);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if !parts.iter().any(|p| !matches!(p, Part::Literal(_))) {
+            return Err(format!(
+                "'{value}' has no placeholders, so every file would get the same name"
+            ));
+        }
+        Ok(Self { parts })
+    }
+
+    /// Fills in the template from `path`. Components the path doesn't have,
+    /// such as the parent of a file in the root folder, are left empty.
+    pub fn render(&self, path: &Path) -> String {
+        let component = |path: Option<&Path>| {
+            path.and_then(Path::file_name)
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default()
+        };
+        // Resolve the folders of relative paths such as `./song.mp3`, leaving
+        // the file name alone in case it's a symlink
+        let parent = path.parent().map(|parent| {
+            let parent = if parent.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                parent
+            };
+            parent
+                .canonicalize()
+                .or_else(|_| std::path::absolute(parent))
+                .unwrap_or_else(|_| parent.to_path_buf())
+        });
+        let parent = parent.as_deref();
+
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => name.push_str(text),
+                Part::Name => name.push_str(&component(Some(path))),
+                Part::Stem => {
+                    if let Some(stem) = path.file_stem() {
+                        name.push_str(&stem.to_string_lossy());
+                    }
+                }
+                Part::Ext => {
+                    if let Some(ext) = path.extension() {
+                        name.push_str(&ext.to_string_lossy());
+                    }
+                }
+                Part::Parent => name.push_str(&component(parent)),
+                Part::Grandparent => name.push_str(&component(parent.and_then(Path::parent))),
+            }
+        }
+        name
+    }
+}
@@ -0,0 +1,147 @@
+//! Adjusting the number of simultaneous uploads to the network, for
+//! --auto-tasks.
+//!
+//! This works like TCP congestion control: the limit grows by one upload
+//! after each round of uploads that didn't slow things down, and is halved
+//! whenever an upload fails.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use indicatif::HumanBytes;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Number of simultaneous uploads to start with.
+const START_TASKS: usize = 2;
+
+/// A round whose throughput is below this fraction of the previous round's
+/// lowers the limit.
+const SLOWDOWN: f64 = 0.8;
+
+/// Sets how many uploads may run at once from their throughput and failures.
+pub struct AutoTasks {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// Uploads currently allowed at once.
+    limit: usize,
+    /// Most uploads ever allowed at once, from --tasks.
+    max: usize,
+    /// Permits to take out of circulation as uploads finish, after the limit
+    /// was lowered while they were in use.
+    debt: usize,
+    /// When the current round started.
+    round_start: Instant,
+    /// Uploads finished in the current round.
+    round_files: usize,
+    /// Bytes uploaded in the current round.
+    round_bytes: u64,
+    /// Throughput of the last round, in bytes per second.
+    last_rate: Option<f64>,
+}
+
+impl AutoTasks {
+    /// Starts with a small limit that can grow to `max`.
+    pub fn new(max: usize) -> Self {
+        let limit = START_TASKS.min(max).max(1);
+        Self {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            state: Mutex::new(State {
+                limit,
+                max,
+                debt: 0,
+                round_start: Instant::now(),
+                round_files: 0,
+                round_bytes: 0,
+                last_rate: None,
+            }),
+        }
+    }
+
+    /// Gets the semaphore uploads wait on.
+    pub fn semaphore(&self) -> Arc<Semaphore> {
+        self.semaphore.clone()
+    }
+
+    /// Records a finished upload of `bytes`. Once as many uploads as the
+    /// limit have finished, the round's throughput decides whether to raise
+    /// or lower it.
+    pub fn success(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.round_files += 1;
+        state.round_bytes += bytes;
+        if state.round_files < state.limit {
+            return;
+        }
+
+        let elapsed = state.round_start.elapsed().as_secs_f64();
+        let rate = state.round_bytes as f64 / elapsed.max(f64::EPSILON);
+        let limit = match state.last_rate {
+            Some(last) if rate < last * SLOWDOWN => state.limit.saturating_sub(1).max(1),
+            _ => (state.limit + 1).min(state.max),
+        };
+        tracing::debug!(
+            tasks = state.limit,
+            "Uploading at {}/s",
+            HumanBytes(rate as u64)
+        );
+        state.last_rate = Some(rate);
+        self.set_limit(&mut state, limit);
+        state.start_round();
+    }
+
+    /// Records a failed upload attempt, halving the limit.
+    pub fn failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let limit = (state.limit / 2).max(1);
+        // The next round shouldn't be compared to one before the failure
+        state.last_rate = None;
+        self.set_limit(&mut state, limit);
+        state.start_round();
+    }
+
+    /// Gives back a permit once its upload is done, unless the limit was
+    /// lowered while it was in use.
+    pub fn release(&self, permit: OwnedSemaphorePermit) {
+        let mut state = self.state.lock().unwrap();
+        if state.debt > 0 {
+            state.debt -= 1;
+            permit.forget();
+        }
+    }
+
+    fn set_limit(&self, state: &mut State, limit: usize) {
+        if limit == state.limit {
+            return;
+        }
+        tracing::info!(
+            "Adjusting simultaneous uploads from {} to {limit}",
+            state.limit
+        );
+        if limit > state.limit {
+            let mut added = limit - state.limit;
+            // Settle any debt before adding permits
+            let repaid = added.min(state.debt);
+            state.debt -= repaid;
+            added -= repaid;
+            self.semaphore.add_permits(added);
+        } else {
+            let removed = state.limit - limit;
+            let forgotten = self.semaphore.forget_permits(removed);
+            state.debt += removed - forgotten;
+        }
+        state.limit = limit;
+    }
+}
+
+impl State {
+    fn start_round(&mut self) {
+        self.round_start = Instant::now();
+        self.round_files = 0;
+        self.round_bytes = 0;
+    }
+}
@@ -0,0 +1,183 @@
+//! Uploading files as they appear in a folder, for --watch.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{
+    connect, db::Library, print_summary, save_device, select::select_file, upload_files,
+    upload_name, Args, Connected, Filters, TaskLimits, Uploads,
+};
+
+/// How long a file's size must stay the same before it's uploaded, so
+/// downloads that are still being written aren't sent half done.
+const SETTLE_TIME: Duration = Duration::from_secs(3);
+
+/// How often waiting files are checked.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long to wait before trying to reach the device again after
+/// reconnecting failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Files that changed recently, waiting for their size to settle.
+#[derive(Default)]
+struct Settling {
+    /// The size each file was last seen at, and when it was first seen at
+    /// that size.
+    files: HashMap<PathBuf, (Option<u64>, Instant)>,
+}
+
+impl Settling {
+    /// Notes that a file was created or changed.
+    fn touch(&mut self, path: PathBuf) {
+        self.files.insert(path, (None, Instant::now()));
+    }
+
+    /// Takes the files whose size hasn't changed for [`SETTLE_TIME`]. Files
+    /// that were removed, or turned out not to be files, are dropped.
+    fn take_settled(&mut self) -> Vec<PathBuf> {
+        let mut settled = Vec::new();
+        self.files.retain(|path, (len, since)| {
+            let current = match std::fs::metadata(path) {
+                Ok(meta) if meta.is_file() => meta.len(),
+                _ => return false,
+            };
+            if *len != Some(current) {
+                *len = Some(current);
+                *since = Instant::now();
+                true
+            } else if since.elapsed() >= SETTLE_TIME {
+                settled.push(path.clone());
+                false
+            } else {
+                true
+            }
+        });
+        settled.sort();
+        settled
+    }
+}
+
+/// Watches `dir` and uploads supported files to the device as they appear,
+/// until the program is stopped.
+///
+/// If the device can't be reached before an upload, it's paired with again,
+/// which picks up a new LAN URL.
+pub async fn run(
+    library: &Library,
+    args: &Args,
+    filters: &Filters,
+    limits: &TaskLimits,
+    dir: &Path,
+    connected: Connected,
+) -> anyhow::Result<()> {
+    let (send, mut changes) = mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => {
+                for path in event.paths {
+                    let _ = send.send(path);
+                }
+            }
+            Ok(_) => {}
+            Err(err) => tracing::warn!("Error watching for changes: {err}"),
+        })?;
+    let mode = if args.recurse {
+        RecursiveMode::Recursive
+    } else {
+        RecursiveMode::NonRecursive
+    };
+    watcher
+        .watch(dir, mode)
+        .with_context(|| format!("Couldn't watch {}", dir.display()))?;
+    println!("Watching {} for new files...", dir.display());
+
+    save_device(library, &connected).await?;
+    let mut device = Arc::new(connected.client);
+    let (id, name) = (connected.id, connected.name);
+
+    let mut settling = Settling::default();
+    let mut queue = Vec::new();
+    let mut retry_at = None;
+    let mut tick = tokio::time::interval(TICK);
+    loop {
+        tokio::select! {
+            Some(path) = changes.recv() => settling.touch(path),
+            _ = tick.tick() => {}
+        }
+        queue.extend(settling.take_settled());
+        if queue.is_empty() || retry_at.is_some_and(|at| Instant::now() < at) {
+            continue;
+        }
+
+        if let Err(err) = device.ping().await {
+            tracing::warn!("Device stopped responding ({err}), pairing again");
+            let saved = library.get_device_by_id(&id).await?;
+            match connect(library, args, saved).await {
+                Ok(connected) => {
+                    save_device(library, &connected).await?;
+                    device = Arc::new(connected.client);
+                    retry_at = None;
+                }
+                Err(err) => {
+                    tracing::warn!("Couldn't reach the device: {err:#}");
+                    retry_at = Some(Instant::now() + RECONNECT_DELAY);
+                    continue;
+                }
+            }
+        }
+
+        let uploaded = library.uploaded_names(&id).await.unwrap_or_else(|err| {
+            tracing::warn!("Couldn't read previous uploads: {err:#}");
+            Default::default()
+        });
+        let mut selected = Vec::new();
+        for path in queue.drain(..) {
+            match select_file(device.as_ref(), &path, filters) {
+                Ok(_) if uploaded.contains(&upload_name(&path, args.name_template.as_ref())) => {
+                    tracing::info!("{}: already uploaded, skipping", path.display());
+                }
+                Ok(file) => selected.push(file),
+                Err(reason) => tracing::info!("{}: skipping, {reason}", path.display()),
+            }
+        }
+        if selected.is_empty() {
+            continue;
+        }
+
+        let (start, started) = (Instant::now(), SystemTime::now());
+        let paths: Vec<_> = selected.iter().map(|file| file.path.clone()).collect();
+        let mut uploads = Uploads::default();
+        match upload_files(library, args, &device, &id, selected, limits, &mut uploads).await {
+            Ok(()) => {
+                if !args.quiet {
+                    print_summary((&id, &name), &uploads.counts, start.elapsed(), args.output);
+                }
+                if let Err(err) = library.mark_synced(&id, device.info(), started).await {
+                    tracing::warn!("Couldn't record sync time: {err:#}");
+                }
+            }
+            // Keep watching, as the next files may well upload. The files
+            // that weren't uploaded are tried again later, and the sync isn't
+            // recorded until they are, so --since-last-sync still picks them
+            // up if watching stops first.
+            Err(err) => {
+                tracing::error!("{err:#}");
+                queue.extend(
+                    paths
+                        .into_iter()
+                        .filter(|path| !uploads.paths.contains(path)),
+                );
+                retry_at = Some(Instant::now() + RECONNECT_DELAY);
+            }
+        }
+    }
+}